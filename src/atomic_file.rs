@@ -0,0 +1,105 @@
+use std::{path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}};
+
+use tokio::{fs::File, io::AsyncWriteExt};
+use walkdir::WalkDir;
+
+use crate::{error::ErrorKind, log, CliOpts};
+
+const TEMP_SUFFIX: &str = ".npmmirs-tmp";
+
+/// Tells apart temporary files of concurrent writers to the same target within a run.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Writes the data to a temporary file next to the target, which is then renamed into place. Readers will either
+/// see the old file or the complete new one, never a partially written file.
+pub async fn write_atomic(opts: &CliOpts, path: &Path, data: &[u8]) -> Result<(), ErrorKind> {
+    let temp_path = temp_path(path);
+
+    let mut file = File::create(&temp_path).await?;
+    file.write_all(data).await?;
+
+    finish_atomic(opts, file, &temp_path, path).await
+}
+
+/// Moves a fully written temporary file into place, flushing it to disk first if requested.
+pub async fn finish_atomic(opts: &CliOpts, mut file: File, temp_path: &Path, path: &Path) -> Result<(), ErrorKind> {
+    file.flush().await?;
+
+    if opts.fsync {
+        file.sync_all().await?;
+    }
+
+    drop(file);
+
+    tokio::fs::rename(temp_path, path).await?;
+
+    if opts.fsync {
+        sync_parent_dir(path).await?;
+    }
+
+    Ok(())
+}
+
+/// Points the symlink at `link` to `original`, replacing whatever was there before in a single step.
+pub async fn symlink_atomic(opts: &CliOpts, original: &Path, link: &Path) -> Result<(), ErrorKind> {
+    let temp_path = temp_path(link);
+
+    tokio::fs::symlink(original, &temp_path).await?;
+    tokio::fs::rename(&temp_path, link).await?;
+
+    if opts.fsync {
+        sync_parent_dir(link).await?;
+    }
+
+    Ok(())
+}
+
+/// Removes temporary files left behind by an interrupted run. Walking the whole output folder takes a while, so
+/// this runs on the blocking pool.
+pub async fn remove_temp_files(opts: &CliOpts) -> Result<u64, ErrorKind> {
+    let output = opts.output.clone();
+    let verbose = opts.verbose;
+
+    tokio::task::spawn_blocking(move || remove_temp_files_in(&output, verbose)).await?
+}
+
+fn remove_temp_files_in(output: &str, verbose: bool) -> Result<u64, ErrorKind> {
+    let mut removed = 0;
+
+    if !Path::new(output).exists() {
+        return Ok(removed)
+    }
+
+    for entry in WalkDir::new(output) {
+        let entry = entry?;
+
+        if entry.file_type().is_dir() || !entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX) {
+            continue
+        }
+
+        if verbose {
+            log(format!("removing stray temporary file {}", entry.path().display()));
+        }
+
+        std::fs::remove_file(entry.path())?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// A temporary file name next to the path that no other writer uses, such as `index.json.1234-5.npmmirs-tmp`.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".{}-{}{TEMP_SUFFIX}", std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+
+    PathBuf::from(temp_path)
+}
+
+async fn sync_parent_dir(path: &Path) -> Result<(), ErrorKind> {
+    if let Some(parent) = path.parent() {
+        File::open(parent).await?.sync_all().await?;
+    }
+
+    Ok(())
+}
@@ -1,25 +1,56 @@
 use std::fmt::Display;
 
-use sha2::{digest::{FixedOutput, Update}, Digest, Sha512};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{de::Error, Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{digest::{FixedOutput, Update}, Digest, Sha256, Sha512};
 
 use crate::error::ErrorKind;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Checksum {
-    Sha512([u8; 64])
+    Sha512([u8; 64]),
+    Sha256([u8; 32]),
+    Sha1([u8; 20]),
 }
 
 impl TryFrom<&str> for Checksum {
     type Error = ErrorKind;
 
+    /// Parses a single subresource integrity hash such as `sha512-<base64>`, or a plain hex digest.
     fn try_from(value: &str) -> std::prelude::v1::Result<Self, Self::Error> {
+        let into_checksum_err = || ErrorKind::IntoChecksum { value: value.to_string() };
+
+        if let Some((algorithm, digest)) = value.split_once('-') {
+            // integrity options come after a '?' and have no bearing on the digest
+            let digest = digest.split_once('?').map(|(digest, _)| digest).unwrap_or(digest);
+            let bytes = BASE64_STANDARD.decode(digest)?;
+
+            return match algorithm {
+                "sha512" => bytes.try_into().map(Self::Sha512).map_err(|_| into_checksum_err()),
+                "sha256" => bytes.try_into().map(Self::Sha256).map_err(|_| into_checksum_err()),
+                "sha1" => bytes.try_into().map(Self::Sha1).map_err(|_| into_checksum_err()),
+                _ => Err(into_checksum_err())
+            }
+        }
+
         match value.len() {
             128 => {
                 let mut bytes = [0_u8; 64];
                 hex::decode_to_slice(value, &mut bytes)?;
                 Ok(bytes.into())
-            }
-            _ => Err(ErrorKind::IntoChecksum { value: value.to_string() })
+            },
+            64 => {
+                let mut bytes = [0_u8; 32];
+                hex::decode_to_slice(value, &mut bytes)?;
+                Ok(Self::Sha256(bytes))
+            },
+            40 => {
+                let mut bytes = [0_u8; 20];
+                hex::decode_to_slice(value, &mut bytes)?;
+                Ok(Self::Sha1(bytes))
+            },
+            _ => Err(into_checksum_err())
         }
     }
 }
@@ -30,18 +61,79 @@ impl From<[u8; 64]> for Checksum {
     }
 }
 
+/// Formats the checksum in its subresource integrity form, which parses back into the same checksum.
 impl Display for Checksum {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Checksum::Sha512(v) => f.write_str(&hex::encode(v)),
-        }
+        f.write_fmt(format_args!("{}-{}", self.algorithm(), BASE64_STANDARD.encode(self.digest())))
+    }
+}
+
+impl Serialize for Checksum {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de> {
+        let value = String::deserialize(deserializer)?;
+
+        Checksum::try_from(value.as_str()).map_err(D::Error::custom)
     }
 }
 
 impl Checksum {
+    /// Parses a subresource integrity string, which may hold several space separated hashes. Hashes using
+    /// algorithms we don't know are left out.
+    pub fn parse_sri(value: &str) -> Vec<Checksum> {
+        value.split_whitespace()
+            .filter_map(|v| Checksum::try_from(v).ok())
+            .collect()
+    }
+
+    /// Picks the strongest checksum to verify a tarball with from its dist info, considering both the integrity
+    /// field and the legacy sha1 shasum.
+    pub fn from_dist(integrity: Option<&str>, shasum: Option<&str>) -> Option<Self> {
+        integrity.map(Checksum::parse_sri)
+            .unwrap_or_default()
+            .into_iter()
+            .chain(shasum.and_then(|v| Checksum::try_from(v).ok()))
+            .max_by_key(Checksum::strength)
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            Checksum::Sha512(_) => "sha512",
+            Checksum::Sha256(_) => "sha256",
+            Checksum::Sha1(_) => "sha1",
+        }
+    }
+
+    pub fn digest(&self) -> &[u8] {
+        match self {
+            Checksum::Sha512(v) => v,
+            Checksum::Sha256(v) => v,
+            Checksum::Sha1(v) => v,
+        }
+    }
+
+    fn strength(&self) -> u8 {
+        match self {
+            Checksum::Sha512(_) => 3,
+            Checksum::Sha256(_) => 2,
+            Checksum::Sha1(_) => 1,
+        }
+    }
+
     pub fn create_hasher(&self) -> Box<dyn Hasher> {
         match self {
             Checksum::Sha512(_) => Box::new(Sha512Hasher::new()),
+            Checksum::Sha256(_) => Box::new(Sha256Hasher::new()),
+            Checksum::Sha1(_) => Box::new(Sha1Hasher::new()),
         }
     }
 }
@@ -71,4 +163,117 @@ impl Hasher for Sha512Hasher {
     fn compute(self: Box<Self>) -> Checksum {
         Checksum::Sha512(self.hasher.finalize_fixed().into())
     }
-}
\ No newline at end of file
+}
+
+pub struct Sha256Hasher {
+    hasher: Sha256
+}
+
+impl Sha256Hasher {
+    pub fn new() -> Self {
+        Self {
+            hasher: Sha256::new()
+        }
+    }
+}
+
+impl Hasher for Sha256Hasher {
+    fn consume(&mut self, data: &[u8]) {
+        Update::update(&mut self.hasher, data)
+    }
+
+    fn compute(self: Box<Self>) -> Checksum {
+        Checksum::Sha256(self.hasher.finalize_fixed().into())
+    }
+}
+
+pub struct Sha1Hasher {
+    hasher: Sha1
+}
+
+impl Sha1Hasher {
+    pub fn new() -> Self {
+        Self {
+            hasher: Sha1::new()
+        }
+    }
+}
+
+impl Hasher for Sha1Hasher {
+    fn consume(&mut self, data: &[u8]) {
+        Update::update(&mut self.hasher, data)
+    }
+
+    fn compute(self: Box<Self>) -> Checksum {
+        Checksum::Sha1(self.hasher.finalize_fixed().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(mut hasher: Box<dyn Hasher>, data: &[u8]) -> Checksum {
+        hasher.consume(data);
+        hasher.compute()
+    }
+
+    fn sha512() -> Checksum {
+        hash(Box::new(Sha512Hasher::new()), b"npmmirs")
+    }
+
+    fn sha256() -> Checksum {
+        hash(Box::new(Sha256Hasher::new()), b"npmmirs")
+    }
+
+    fn sha1() -> Checksum {
+        hash(Box::new(Sha1Hasher::new()), b"npmmirs")
+    }
+
+    #[test]
+    fn sri_with_mixed_and_unknown_algorithms() {
+        let sri = format!("{} md5-AAAA {} sha384-{}", sha1(), sha512(), BASE64_STANDARD.encode([0_u8; 48]));
+
+        assert_eq!(Checksum::parse_sri(&sri), vec![sha1(), sha512()]);
+        assert_eq!(Checksum::from_dist(Some(&sri), None), Some(sha512()));
+    }
+
+    #[test]
+    fn sri_options_are_ignored() {
+        assert_eq!(Checksum::parse_sri(&format!("{}?foo=bar", sha256())), vec![sha256()]);
+    }
+
+    #[test]
+    fn integrity_wins_over_shasum() {
+        let shasum = hex::encode(sha1().digest());
+
+        assert_eq!(Checksum::from_dist(Some(&sha512().to_string()), Some(&shasum)), Some(sha512()));
+        assert_eq!(Checksum::from_dist(None, Some(&shasum)), Some(sha1()));
+        assert_eq!(Checksum::from_dist(Some("md5-AAAA"), Some(&shasum)), Some(sha1()));
+        assert_eq!(Checksum::from_dist(None, None), None);
+    }
+
+    #[test]
+    fn hex_digests() {
+        for checksum in [sha512(), sha256(), sha1()] {
+            assert_eq!(Checksum::try_from(hex::encode(checksum.digest()).as_str()).unwrap(), checksum);
+        }
+
+        assert!(Checksum::try_from("abcd").is_err());
+        assert!(Checksum::try_from("zz".repeat(20).as_str()).is_err());
+    }
+
+    #[test]
+    fn wrong_digest_lengths() {
+        assert!(Checksum::try_from(format!("sha512-{}", BASE64_STANDARD.encode([0_u8; 32])).as_str()).is_err());
+        assert!(Checksum::try_from(format!("sha256-{}", BASE64_STANDARD.encode([0_u8; 20])).as_str()).is_err());
+        assert!(Checksum::try_from(format!("sha1-{}", BASE64_STANDARD.encode([0_u8; 64])).as_str()).is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for checksum in [sha512(), sha256(), sha1()] {
+            assert_eq!(Checksum::try_from(checksum.to_string().as_str()).unwrap(), checksum);
+        }
+    }
+}
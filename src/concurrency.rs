@@ -0,0 +1,293 @@
+use std::{str::FromStr, sync::Mutex, time::{Duration, Instant}};
+
+use ahash::HashMap;
+use compact_str::{CompactString, ToCompactString};
+use reqwest::Url;
+use tokio::sync::{futures::Notified, Notify};
+
+use crate::{log, CliOpts};
+
+/// How much the concurrency limit shrinks on congestion.
+const DECREASE_FACTOR: f64 = 0.7;
+
+/// Latency this many times the baseline counts as congestion.
+const LATENCY_TOLERANCE: f64 = 2.0;
+
+/// A cap on concurrent downloads from a single host, in the form `<host>=<count>`.
+#[derive(Clone, Debug)]
+pub struct HostLimit {
+    host: CompactString,
+    limit: usize,
+}
+
+impl FromStr for HostLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, limit) = s.split_once('=')
+            .ok_or_else(|| format!("expected <host>=<count>, got '{s}'"))?;
+
+        let limit = limit.parse::<usize>()
+            .map_err(|e| format!("invalid count '{limit}': {e}"))?;
+
+        if host.is_empty() || limit == 0 {
+            return Err(format!("expected <host>=<count> with a count above 0, got '{s}'"))
+        }
+
+        // urls are matched by their host, which is always lowercase once parsed
+        Ok(Self { host: host.to_ascii_lowercase().to_compact_string(), limit })
+    }
+}
+
+/// The result of a single download attempt, as far as the concurrency limit is concerned.
+pub enum Outcome {
+    Success,
+    /// The registry is overwhelmed or throttling us, such as a 429 or a timeout.
+    Congested,
+    /// Failed for reasons that have nothing to do with load, such as a 404.
+    Other,
+}
+
+/// Limits concurrent downloads, overall and per host. In adaptive mode, the overall limit grows by one per limit's
+/// worth of successful downloads, and shrinks whenever the registry shows signs of congestion.
+pub struct Concurrency {
+    adaptive: bool,
+    min: f64,
+    max: f64,
+    per_host: Option<usize>,
+    host_limits: Vec<HostLimit>,
+    verbose: bool,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+struct State {
+    limit: f64,
+    active: usize,
+    active_per_host: HashMap<CompactString, usize>,
+    baseline_latency: Option<Duration>,
+    latency: Option<Duration>,
+    last_decrease: Option<Instant>,
+}
+
+impl Concurrency {
+    pub fn new(opts: &CliOpts) -> Self {
+        let initial = opts.dl_threads.max(1) as f64;
+
+        Self {
+            adaptive: opts.adaptive_concurrency,
+            min: 1.0,
+            max: opts.max_dl_threads.max(opts.dl_threads).max(1) as f64,
+            per_host: opts.max_per_host,
+            host_limits: opts.host_limit.clone(),
+            verbose: opts.verbose,
+            state: Mutex::new(State {
+                limit: initial,
+                active: 0,
+                active_per_host: HashMap::default(),
+                baseline_latency: None,
+                latency: None,
+                last_decrease: None,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// The number of download tasks needed to reach the highest possible limit.
+    pub fn max_tasks(&self) -> usize {
+        match self.adaptive {
+            true => self.max as usize,
+            false => self.state.lock().unwrap().limit as usize,
+        }
+    }
+
+    /// Waits until another download from the host of the url may start.
+    pub async fn acquire(&self, url: &str) -> Permit<'_> {
+        let host = host_of(url);
+
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(permit) = self.try_acquire(host.as_ref()) {
+                return permit
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Lets another download from the host start right away, if there's room for it.
+    pub fn try_acquire(&self, host: Option<&CompactString>) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.active as f64 >= state.limit.floor() {
+            return None
+        }
+
+        if let Some(host) = host {
+            let active = state.active_per_host.get(host).copied().unwrap_or(0);
+
+            if self.host_limit(host).is_some_and(|limit| active >= limit) {
+                return None
+            }
+
+            *state.active_per_host.entry(host.clone()).or_default() += 1;
+        }
+
+        state.active += 1;
+
+        Some(Permit { concurrency: self, host: host.cloned() })
+    }
+
+    /// Resolves once there might be room for more downloads, because one finished or the limit grew.
+    pub fn capacity_changed(&self) -> Notified<'_> {
+        self.notify.notified()
+    }
+
+    fn host_limit(&self, host: &str) -> Option<usize> {
+        let without_port = host.rsplit_once(':').map(|(h, _)| h).unwrap_or(host);
+
+        self.host_limits.iter()
+            .find(|l| l.host == host)
+            .or_else(|| self.host_limits.iter().find(|l| l.host == without_port))
+            .map(|l| l.limit)
+            .or(self.per_host)
+    }
+
+    /// Feeds the time until the response headers arrived into the congestion detection.
+    pub fn record_latency(&self, latency: Duration) {
+        if !self.adaptive {
+            return
+        }
+
+        let congested = {
+            let mut state = self.state.lock().unwrap();
+
+            let average = match state.latency {
+                Some(average) => average.mul_f64(0.8) + latency.mul_f64(0.2),
+                None => latency
+            };
+
+            state.latency = Some(average);
+
+            // the baseline follows improvements right away, but only creeps up, so that a slow registry doesn't
+            // look congested forever
+            let baseline = match state.baseline_latency {
+                Some(baseline) if latency < baseline => latency,
+                Some(baseline) => baseline + (latency - baseline).mul_f64(0.01),
+                None => latency
+            };
+
+            state.baseline_latency = Some(baseline);
+
+            average.as_secs_f64() > baseline.as_secs_f64() * LATENCY_TOLERANCE
+        };
+
+        if congested {
+            self.record(Outcome::Congested);
+        }
+    }
+
+    pub fn record(&self, outcome: Outcome) {
+        if !self.adaptive {
+            return
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        match outcome {
+            Outcome::Success => {
+                let limit = state.limit;
+                state.limit = (limit + 1.0 / limit).min(self.max);
+
+                if state.limit.floor() > limit.floor() {
+                    self.notify.notify_waiters();
+                }
+            },
+            Outcome::Congested => {
+                // everything in flight when congestion starts will report it, only react once per round trip
+                let cooldown = state.latency.unwrap_or_default().max(Duration::from_secs(1));
+
+                if state.last_decrease.is_some_and(|last| last.elapsed() < cooldown) {
+                    return
+                }
+
+                state.last_decrease = Some(Instant::now());
+                state.limit = (state.limit * DECREASE_FACTOR).max(self.min);
+
+                if self.verbose {
+                    log(format!("registry congested, reducing concurrent downloads to {}", state.limit.floor()));
+                }
+            },
+            Outcome::Other => ()
+        }
+    }
+}
+
+/// Allows a single download to run, until dropped.
+pub struct Permit<'a> {
+    concurrency: &'a Concurrency,
+    host: Option<CompactString>,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.concurrency.state.lock().unwrap();
+
+        state.active -= 1;
+
+        if let Some(host) = &self.host && let Some(active) = state.active_per_host.get_mut(host) {
+            *active -= 1;
+
+            if *active == 0 {
+                state.active_per_host.remove(host);
+            }
+        }
+
+        self.concurrency.notify.notify_waiters();
+    }
+}
+
+/// The host and port of a url, local paths have none. Default ports are spelled out, so that limits for
+/// `registry.npmjs.org:443` apply to `https://registry.npmjs.org` as well.
+pub fn host_of(url: &str) -> Option<CompactString> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+
+    match url.port_or_known_default() {
+        Some(port) => Some(compact_str::format_compact!("{host}:{port}")),
+        None => Some(host.to_compact_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn concurrency(args: &[&str]) -> Concurrency {
+        Concurrency::new(&CliOpts::parse_from(["npmmirs", "--dl-threads", "8"].iter().chain(args)))
+    }
+
+    fn acquire_all<'a>(concurrency: &'a Concurrency, url: &str) -> Vec<Permit<'a>> {
+        std::iter::from_fn(|| concurrency.try_acquire(host_of(url).as_ref())).take(8).collect()
+    }
+
+    #[test]
+    fn host_limits_match_default_ports() {
+        let concurrency = concurrency(&["--host-limit", "registry.npmjs.org:443=2"]);
+
+        assert_eq!(acquire_all(&concurrency, "https://registry.npmjs.org/x").len(), 2);
+        assert_eq!(acquire_all(&concurrency, "https://registry.npmjs.org:8443/x").len(), 8);
+    }
+
+    #[test]
+    fn host_limits_match_hosts_case_insensitively() {
+        let concurrency = concurrency(&["--host-limit", "Registry.NPMjs.org=3"]);
+
+        assert_eq!(acquire_all(&concurrency, "https://registry.npmjs.org/x").len(), 3);
+    }
+}
@@ -1,351 +1,1060 @@
 
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{path::Path, sync::Arc};
 
-use async_channel::{bounded, Sender, Receiver};
 use compact_str::{CompactString, ToCompactString};
-use reqwest::header::{HeaderMap, ETAG};
-use reqwest::{header::CONTENT_LENGTH, Client, StatusCode};
-use tokio::fs::symlink;
-use tokio::sync::RwLock;
-use tokio::{fs::File, io::{AsyncWriteExt, BufWriter}, task::JoinHandle};
-
-use crate::checksum::Checksum;
+use indicatif::ProgressBar;
+use reqwest::header::{HeaderMap, ACCEPT, ACCEPT_RANGES, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE};
+use reqwest::{header::CONTENT_LENGTH, RequestBuilder, Response, StatusCode, Url};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep, Instant};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncReadExt, AsyncWriteExt, BufWriter}, task::JoinHandle};
+
+use crate::atomic_file::{finish_atomic, symlink_atomic, temp_path, write_atomic};
+use crate::checksum::{Checksum, Hasher};
+use crate::concurrency::{Concurrency, Outcome, Permit};
+use crate::http::{HttpClients, StallDetector};
+use crate::limiter::Limiter;
 use crate::meta_cache::MetaCache;
 use crate::metadata::package_index::read_package_idx;
-use crate::metadata::{local_metadata_path, package_index::{write_package_idx, PackageIndex, TarballUrl}, sparse_metadata::SparseMetadata};
-use crate::progress::Progress;
+use crate::metadata::{local_abbreviated_metadata_path, local_metadata_checked_path, local_metadata_path, MetadataFormat, ABBREVIATED_ACCEPT, package_index::{read_idx_validators, write_package_idx, PackageIndex, TarballUrl, Validators}, sparse_metadata::{read_sparse_metadata, SparseMetadata}};
+use crate::progress::{Progress, ProgressPart};
+use crate::protection::{registry_url_for, url_is_under};
+use crate::retry::{get_retry_after, RetryPolicy};
+use crate::scheduler::Scheduler;
+use crate::shutdown::Shutdown;
+use crate::upstream::{contained_path, link_name, local_path};
 use crate::error::{ErrorKind, Result};
 use crate::{log, CliOpts};
 
 pub struct DownloadTask {
     opts: Arc<CliOpts>,
-    meta_cache: Arc<RwLock<MetaCache>>,
-    receiver: Receiver<Download>,
+    meta_cache: Arc<MetaCache>,
+    scheduler: Arc<Scheduler>,
     progress: Progress,
-    http_client: Client,
+    http_clients: HttpClients,
+    retry_policy: Arc<RetryPolicy>,
+    limiter: Arc<Limiter>,
+    concurrency: Arc<Concurrency>,
+    arrivals: UnboundedSender<CompactString>,
+    shutdown: Shutdown,
+    /// What the current attempt added to the total bytes, so it can be taken back when retrying. Every task runs
+    /// one download at a time.
+    attempt_total_bytes: AtomicU64,
 }
 
 impl DownloadTask {
-    async fn download_and_track(&self, buf: &mut Vec<u8>, dl: Download) -> Result<()> {
-        match self.download(buf, dl, |b| self.progress.bytes.inc_success(b)).await {
-            Ok(true) => self.progress.files.inc_success(1),
-            Ok(false) => self.progress.files.inc_skipped(1),
+    /// Runs a download that was handed out along with its permit, retrying it as needed. Retries wait for a permit
+    /// of their own.
+    async fn download_and_track(&self, buf: &mut Vec<u8>, dl: Download, permit: Permit<'_>) -> Result<()> {
+        let mut retry = 0;
+        let mut permit = Some(permit);
+        let is_tarball = matches!(dl, Download::Tarball { .. });
+
+        let result = loop {
+            self.progress.inc_attempts();
+            self.attempt_total_bytes.store(0, Ordering::SeqCst);
+            buf.clear();
+
+            let mut received = 0;
+
+            let permit = match permit.take() {
+                Some(permit) => permit,
+                None => self.concurrency.acquire(dl.url()).await
+            };
+            let result = self.download(buf, dl.clone(), |b| {
+                received += b;
+                self.progress.bytes.inc_success(b);
+
+                if is_tarball {
+                    self.progress.tarball_bytes.fetch_add(b, Ordering::SeqCst);
+                }
+            }).await;
+            drop(permit);
+
+            self.concurrency.record(match &result {
+                Ok(_) => Outcome::Success,
+                Err(e) if e.is_congestion() => Outcome::Congested,
+                Err(_) => Outcome::Other,
+            });
+
+            match result {
+                Err(e) if e.is_retryable() && retry < self.retry_policy.retries && !self.shutdown.is_triggered() => {
+                    let Some(delay) = self.retry_policy.delay(retry + 1, e.retry_after()) else {
+                        log(format!("not retrying {}, the registry asks to wait longer than the maximum retry delay: {e}", dl.url()));
+                        break Err(e)
+                    };
+
+                    retry += 1;
+                    self.progress.inc_retries();
+
+                    // the next attempt counts its bytes again
+                    self.progress.bytes.dec_total(self.attempt_total_bytes.load(Ordering::SeqCst));
+                    self.progress.bytes.dec_success(received);
+
+                    if is_tarball {
+                        self.progress.tarball_bytes.fetch_sub(received, Ordering::SeqCst);
+                    }
+
+                    if self.opts.verbose {
+                        log(format!("retrying {} in {delay:?} ({retry}/{}): {e}", dl.url(), self.retry_policy.retries));
+                    }
+
+                    sleep(delay).await;
+                },
+                result => break result
+            }
+        };
+
+        // when the registry can't be reached, stale metadata is better than none at all. Anything else, such as
+        // the package being gone or its metadata being broken, is reported as is
+        if let Err(e) = &result && e.is_unreachable() && let Download::Metadata { package, .. } = &dl {
+            buf.clear();
+
+            if let Some(len) = self.read_local_idx(buf, package).await {
+                log(format!("using stale local metadata for {package}, the registry is unreachable: {e}"));
+
+                self.meta_cache.insert(package, &buf[..len]);
+            }
+        }
+
+        let part = match is_tarball {
+            true => &self.progress.tarballs,
+            false => &ProgressPart::default()
+        };
+
+        match result {
+            Ok(true) => {
+                self.progress.files.inc_success(1);
+                part.inc_success(1);
+            },
+            Ok(false) => {
+                self.progress.files.inc_skipped(1);
+                part.inc_skipped(1);
+            },
             Err(e) => {
                 match e {
-                    ErrorKind::Download { url: _, status_code: StatusCode::NOT_FOUND } => {
+                    ErrorKind::Download { status_code: StatusCode::NOT_FOUND, .. } => {
                         self.progress.files.inc_skipped(1);
+                        part.inc_skipped(1);
                     },
                     ErrorKind::Checksum { .. } |
-                    ErrorKind::Download { .. } => self.progress.files.inc_failed(1),
-                    _ => self.progress.files.inc_skipped(1),
+                    ErrorKind::Size { .. } |
+                    ErrorKind::Stalled { .. } |
+                    ErrorKind::Reqwest(_) |
+                    ErrorKind::Download { .. } => {
+                        log(format!("failed downloading {}: {e}", dl.url()));
+                        self.progress.files.inc_failed(1);
+                        part.inc_failed(1);
+                    },
+                    _ => {
+                        self.progress.files.inc_skipped(1);
+                        part.inc_skipped(1);
+                    },
                 }
             }
         }
 
+        self.scheduler.finish(&dl);
+
+        // whatever happened, the resolver can move on with this package
+        if let Download::Metadata { package, .. } = dl {
+            _ = self.arrivals.send(package);
+        }
+
         Ok(())
     }
 
+    fn inc_total_bytes(&self, len: u64) {
+        self.progress.bytes.inc_total(len);
+        self.attempt_total_bytes.fetch_add(len, Ordering::SeqCst);
+    }
+
     async fn download<F>(&self, buf: &mut Vec<u8>, dl: Download, progress_cb: F) -> Result<bool> where F: FnMut(u64) { 
         match dl {
-            Download::Metadata { url, target_path, package } =>
-                self.download_metadata(buf, package, url, target_path, progress_cb).await,
-            Download::Tarball { url, target_path, checksum } =>
+            Download::Metadata { url, target_path, package, source } =>
+                self.download_metadata(buf, package, url, target_path, source, progress_cb).await,
+            Download::Tarball { url, target_path, checksum, source: Some(source_path), .. } =>
+                self.copy_local_tarball(url, &source_path, target_path, checksum, progress_cb).await,
+            Download::Tarball { url, target_path, checksum, .. } =>
                 self.download_tarball(url, target_path, checksum, progress_cb).await,
         }
     }
 
 
-    async fn needs_downloading(&self, url: &str, target_path: &Path) -> Result<bool> {
-        let head_response = match self.http_client.head(url).send().await {
-            Ok(r) if r.status().is_success() => r,
-            _ => return Ok(false)
+    /// Sends a request, keeping track of how long the registry takes to respond.
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let started = Instant::now();
+        let response = request.send().await?;
+
+        self.concurrency.record_latency(started.elapsed());
+
+        Ok(response)
+    }
+
+    /// Loads the local idx of a package into `buf`, if there is a usable one.
+    async fn read_local_idx(&self, buf: &mut Vec<u8>, package: &str) -> Option<usize> {
+        match read_package_idx(&self.opts, buf, package).await {
+            Ok(len) => Some(len),
+            Err(ErrorKind::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+            // broken or from an older version, rebuild it from the registry
+            Err(e) => {
+                if self.opts.verbose {
+                    log(format!("rebuilding idx for {package}: {e}"));
+                }
+
+                buf.clear();
+                None
+            }
+        }
+    }
+
+    async fn download_metadata<F>(&self, buf: &mut Vec<u8>, package: CompactString, url: String, target_path: PathBuf, source: Option<PathBuf>, mut progress_cb: F) -> Result<bool> where F: FnMut(u64) { 
+        if self.is_fresh(&package, &target_path).await && let Some(len) = self.read_local_idx(buf, &package).await {
+            self.meta_cache.insert(&package, &buf[..len]);
+            return Ok(false)
+        }
+
+        if let Some(source_dir) = source {
+            return self.copy_local_metadata(buf, package, &source_dir, target_path, progress_cb).await
+        }
+
+        let mut request = self.http_clients.for_url(&url).get(url.as_str());
+
+        if self.opts.metadata_format.is_abbreviated() {
+            request = request.header(ACCEPT, ABBREVIATED_ACCEPT);
+        }
+
+        // a GET without a body can always be cloned
+        let unconditional_request = request.try_clone().expect("metadata request is not cloneable");
+
+        // the idx remembers the validators of the metadata it was built from, which lets the registry tell us
+        // that nothing changed instead of sending the whole document again
+        if target_path.exists() && let Ok(validators) = read_idx_validators(&self.opts, &package).await {
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag.as_str());
+            }
+
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+            }
+        }
+
+        self.limiter.request().await;
+
+        let mut response = self.send(request).await?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(len) = self.read_local_idx(buf, &package).await {
+                self.meta_cache.insert(&package, &buf[..len]);
+
+                let full_target_path = local_metadata_path(&self.opts, &package);
+
+                if self.opts.metadata_format == MetadataFormat::Both && !full_target_path.exists() {
+                    self.download_full_metadata(&url, &full_target_path, &mut progress_cb).await?;
+                    self.mark_checked(&package).await?;
+                    return Ok(true)
+                }
+
+                self.mark_checked(&package).await?;
+                return Ok(false)
+            }
+
+            // the idx broke since its validators were read, so there is nothing to keep
+            self.limiter.request().await;
+            response = self.send(unconditional_request).await?;
+        }
+
+        if !response.status().is_success() {
+            return Err(status_error(url, &response))
+        }
+
+        buf.clear();
+
+        let validators = Validators {
+            etag: response.headers().get(ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            last_modified: response.headers().get(LAST_MODIFIED)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
         };
 
-        let Some(etag) = get_etag(head_response.headers()) else {
-            return Ok(true)
+        let etag_name = get_etag(response.headers()).map(|v| v.to_string());
+        let temp_path = temp_path(&metadata_file_path(&target_path, etag_name.as_deref()));
+
+        self.read_body_to_file(&mut response, &temp_path, &mut progress_cb).await?;
+
+        let metadata = self.parse_metadata(&url, &temp_path).await?;
+        self.store_metadata(&temp_path, &target_path, etag_name.as_deref()).await?;
+
+        let package = self.index_metadata(buf, &target_path, metadata, validators).await?;
+
+        // the abbreviated document changes whenever a version does, so there is no point checking the full
+        // document for changes unless the abbreviated one changed
+        if self.opts.metadata_format == MetadataFormat::Both {
+            self.download_full_metadata(&url, &local_metadata_path(&self.opts, &package), &mut progress_cb).await?;
+        }
+
+        self.mark_checked(&package).await?;
+
+        Ok(true)
+    }
+
+    /// Whether the metadata of the package was checked with the registry within the configured ttl.
+    async fn is_fresh(&self, package: &str, target_path: &Path) -> bool {
+        let Some(ttl) = self.opts.metadata_ttl else {
+            return false
+        };
+
+        if self.opts.refresh_packages.iter().any(|p| p.matches(package)) || !target_path.exists() {
+            return false
+        }
+
+        let checked = match tokio::fs::read_to_string(local_metadata_checked_path(&self.opts, package)).await {
+            Ok(v) => v,
+            Err(_) => return false
+        };
+
+        let Ok(checked) = checked.trim().parse::<u64>() else {
+            return false
         };
 
-        let etag_path = target_path.parent().unwrap().join(etag);
+        let checked = UNIX_EPOCH + Duration::from_secs(checked);
+
+        // a clock that went backwards makes the metadata stale rather than fresh forever
+        SystemTime::now().duration_since(checked).is_ok_and(|age| age < ttl)
+    }
+
+    /// Records that the metadata of the package was just checked with the registry.
+    async fn mark_checked(&self, package: &str) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        write_atomic(&self.opts, &local_metadata_checked_path(&self.opts, package), now.to_string().as_bytes()).await
+    }
+
+    /// Parses a metadata document written to a temporary file, removing the file if it isn't valid.
+    async fn parse_metadata(&self, url: &str, temp_path: &Path) -> Result<SparseMetadata> {
+        match read_sparse_metadata(temp_path).await {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                log(format!("unable to parse sparse version of package metadata {url}: {e}"));
+                remove_if_exists(temp_path).await?;
+                Err(e)
+            },
+        }
+    }
 
-        if etag_path.exists() {
+    /// Writes the idx of a stored metadata document. Returns the name of the package.
+    async fn index_metadata(&self, buf: &mut Vec<u8>, target_path: &Path, sparse_metadata: SparseMetadata, validators: Validators) -> Result<CompactString> {
+        let package = sparse_metadata.name.to_compact_string();
+
+        let pkg_idx = PackageIndex::from_sparse(&self.opts, sparse_metadata);
+        let idx_path = target_path.parent().unwrap().join("index.json.idx");
+
+        write_package_idx(&self.opts, buf, &package, &idx_path, pkg_idx, &validators, &self.meta_cache).await?;
+
+        Ok(package)
+    }
+
+    /// Copies the metadata of a package from the output folder of another mirror. The documents there are named
+    /// after their etag, so a link pointing to the same name as ours means nothing changed.
+    async fn copy_local_metadata<F>(&self, buf: &mut Vec<u8>, package: CompactString, source_dir: &Path, target_path: PathBuf, mut progress_cb: F) -> Result<bool> where F: FnMut(u64) {
+        let corgi_source_path = source_dir.join("index.corgi.json");
+
+        // the full document works just as well for resolving, it's only bigger
+        let source_path = match self.opts.metadata_format.is_abbreviated() && corgi_source_path.exists() {
+            true => corgi_source_path,
+            false => source_dir.join("index.json")
+        };
+
+        let etag_name = link_name(&source_path).await?;
+
+        if etag_name.is_some() && etag_name == link_name(&target_path).await? && let Some(len) = self.read_local_idx(buf, &package).await {
+            self.meta_cache.insert(&package, &buf[..len]);
+
+            let full_target_path = local_metadata_path(&self.opts, &package);
+
+            if self.opts.metadata_format == MetadataFormat::Both && !full_target_path.exists() {
+                self.copy_local_full_metadata(source_dir, &full_target_path, &mut progress_cb).await?;
+                self.mark_checked(&package).await?;
+                return Ok(true)
+            }
+
+            self.mark_checked(&package).await?;
             return Ok(false)
         }
-        
+
+        let temp_path = self.copy_to_temp(&source_path, &target_path, etag_name.as_deref(), &mut progress_cb).await?;
+
+        let metadata = self.parse_metadata(&source_path.to_string_lossy(), &temp_path).await?;
+        self.store_metadata(&temp_path, &target_path, etag_name.as_deref()).await?;
+
+        let package = self.index_metadata(buf, &target_path, metadata, Validators::default()).await?;
+
+        if self.opts.metadata_format == MetadataFormat::Both {
+            self.copy_local_full_metadata(source_dir, &local_metadata_path(&self.opts, &package), &mut progress_cb).await?;
+        }
+
+        self.mark_checked(&package).await?;
+
         Ok(true)
     }
 
-    async fn download_metadata<F>(&self, buf: &mut Vec<u8>, package: CompactString, url: String, target_path: PathBuf, mut progress_cb: F) -> Result<bool> where F: FnMut(u64) { 
-        if self.needs_downloading(&url, &target_path).await? {
-            let mut response = self.http_client.get(url.as_str()).send().await?;
+    /// Copies the full metadata document of a package from another mirror, only to be stored for serving.
+    async fn copy_local_full_metadata<F>(&self, source_dir: &Path, target_path: &Path, progress_cb: &mut F) -> Result<()> where F: FnMut(u64) {
+        let source_path = source_dir.join("index.json");
+        let etag_name = link_name(&source_path).await?;
 
-            if !response.status().is_success() {
-                return Err(ErrorKind::Download { url, status_code: response.status() })
-            }
+        let temp_path = self.copy_to_temp(&source_path, target_path, etag_name.as_deref(), progress_cb).await?;
 
-            let (real_target_path, is_etag) = match get_etag(response.headers()) {
-                Some(etag) => (target_path.parent().unwrap().join(etag), true),
-                None => (target_path.clone(), false),
-            };
+        self.store_metadata(&temp_path, target_path, etag_name.as_deref()).await
+    }
 
-            create_dirs(&real_target_path).await?;
+    /// Copies a metadata document from another mirror to a temporary file next to where it ends up.
+    async fn copy_to_temp<F>(&self, source_path: &Path, link_path: &Path, etag: Option<&str>, progress_cb: &mut F) -> Result<PathBuf> where F: FnMut(u64) {
+        let temp_path = temp_path(&metadata_file_path(link_path, etag));
 
-            if let Some(content_len) = response.headers().get(CONTENT_LENGTH) {
-                let size: u64 = content_len.to_str().expect("junk in content length").parse::<u64>()?;
-                self.progress.bytes.inc_total(size);
-                buf.reserve(size as usize);
-            }
+        create_dirs(link_path).await?;
+
+        let len = tokio::fs::copy(source_path, &temp_path).await?;
+
+        self.inc_total_bytes(len);
+        progress_cb(len);
+
+        Ok(temp_path)
+    }
+
+    /// Downloads the full metadata document of a package, only to be stored for serving.
+    async fn download_full_metadata<F>(&self, url: &str, target_path: &Path, progress_cb: &mut F) -> Result<()> where F: FnMut(u64) {
+        self.limiter.request().await;
+
+        let mut response = self.send(self.http_clients.for_url(url).get(url)).await?;
+
+        if !response.status().is_success() {
+            return Err(status_error(url.to_string(), &response))
+        }
+
+        let etag_name = get_etag(response.headers()).map(|v| v.to_string());
+        let temp_path = temp_path(&metadata_file_path(target_path, etag_name.as_deref()));
+
+        self.read_body_to_file(&mut response, &temp_path, progress_cb).await?;
+
+        self.store_metadata(&temp_path, target_path, etag_name.as_deref()).await
+    }
+
+    /// Streams a metadata document into a temporary file, so that even the largest documents never have to fit
+    /// in memory. The file is removed again if the download fails.
+    async fn read_body_to_file<F>(&self, response: &mut Response, temp_path: &Path, progress_cb: &mut F) -> Result<()> where F: FnMut(u64) {
+        if let Some(size) = get_content_length(response.headers())? {
+            self.inc_total_bytes(size);
+        }
+
+        create_dirs(temp_path).await?;
+
+        let result = async {
+            let mut output = BufWriter::new(File::create(temp_path).await?);
+            let mut stall_detector = StallDetector::new(&self.opts);
 
             while let Some(chunk) = response.chunk().await? {
-                AsyncWriteExt::write_all(buf, &chunk).await?;
-        
+                stall_detector.check(response.url().as_str(), chunk.len())?;
+                output.write_all(&chunk).await?;
+
                 progress_cb(chunk.len() as u64);
+                stall_detector.pause(self.limiter.bytes(chunk.len())).await;
             }
 
-            let sparse_metadata: SparseMetadata = match serde_json::from_slice(buf) {
-                Ok(v) => v,
-                Err(e) => {
-                    log(format!("unable to parse sparse version of package metadata {url}: {e}"));
-                    return Err(e.into())
-                },
-            };
+            output.flush().await?;
 
-            tokio::fs::write(&real_target_path, &mut *buf).await?;
+            Ok(())
+        }.await;
 
-            let package = sparse_metadata.name.to_compact_string();
+        if result.is_err() {
+            remove_if_exists(temp_path).await?;
+        }
 
-            let pkg_idx = PackageIndex::from_sparse(&self.opts, sparse_metadata);
-            let idx_path = target_path.parent().unwrap().join("index.json.idx");
+        result
+    }
 
-            write_package_idx(buf, &package, &idx_path, pkg_idx, &self.meta_cache).await?;
+    /// Moves a metadata document from its temporary file into a file named after its etag, and points the link at
+    /// it. Without an etag, the document is moved to the link path directly.
+    async fn store_metadata(&self, temp_path: &Path, link_path: &Path, etag: Option<&str>) -> Result<()> {
+        let real_target_path = metadata_file_path(link_path, etag);
 
-            // we can't really be clever about this if we don't have an etag
-            if !is_etag {
-                return Ok(true)
+        finish_atomic(&self.opts, File::open(temp_path).await?, temp_path, &real_target_path).await?;
+
+        // we can't really be clever about this if we don't have an etag
+        let Some(etag) = etag else {
+            return Ok(())
+        };
+
+        let old_target = match tokio::fs::read_link(link_path).await {
+            Ok(old) => Some(link_path.parent().unwrap().join(old)),
+            // not found, or not a symlink
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound || e.kind() == std::io::ErrorKind::InvalidInput => None,
+            Err(e) => return Err(e.into())
+        };
+
+        symlink_atomic(&self.opts, Path::new(etag), link_path).await?;
+
+        if let Some(old_target) = old_target && old_target != real_target_path {
+            remove_if_exists(&old_target).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies a tarball from the output folder of another mirror, verifying it just like a download.
+    async fn copy_local_tarball<F>(&self, url: String, source_path: &Path, target_path: PathBuf, checksum: Option<Checksum>, mut progress_cb: F) -> Result<bool> where F: FnMut(u64) {
+        if target_path.exists() {
+            return Ok(false)
+        }
+
+        let mut input = File::open(source_path).await?;
+
+        self.inc_total_bytes(input.metadata().await?.len());
+
+        create_dirs(&target_path).await?;
+
+        let temp_path = temp_path(&target_path);
+        let mut output = BufWriter::new(File::create(&temp_path).await?);
+        let mut hasher = checksum.as_ref().map(|c| c.create_hasher());
+        let mut chunk = vec![0u8; 64 * 1024];
+
+        loop {
+            let len = input.read(&mut chunk).await?;
+
+            if len == 0 {
+                break
             }
 
-            match tokio::fs::read_link(&target_path).await {
-                Ok(old) => {
-                    match tokio::fs::remove_file(&old).await {
-                        Ok(_) => (),
-                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
-                        Err(e) => return Err(e.into())
-                    }
-                },
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
-                Err(e) => return Err(e.into())
-            };
+            output.write_all(&chunk[..len]).await?;
 
-            match tokio::fs::remove_file(&target_path).await {
-                Ok(_) => (),
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
-                Err(e) => return Err(e.into())
+            if let Some(hasher) = &mut hasher {
+                hasher.consume(&chunk[..len]);
             }
 
-            symlink(&real_target_path.file_name().unwrap(), target_path).await?;
+            progress_cb(len as u64);
+        }
 
-            Ok(true)
-        } else {
-            let len = read_package_idx(&self.opts, buf, &package).await?;
+        output.flush().await?;
+        let file = output.into_inner();
+
+        if let (Some(expected_checksum), Some(hasher)) = (checksum, hasher) {
+            let checksum = hasher.compute();
 
-            self.meta_cache.write().await.insert(&package, &buf[..len]);
+            if expected_checksum != checksum {
+                remove_if_exists(&temp_path).await?;
 
-            Ok(false)
+                return Err(ErrorKind::Checksum { 
+                    url, 
+                    expected: expected_checksum.to_string(), 
+                    hash: checksum.to_string() 
+                })
+            }
         }
+
+        finish_atomic(&self.opts, file, &temp_path, &target_path).await?;
+
+        Ok(true)
     }
 
     async fn download_tarball<F>(&self, url: String, target_path: PathBuf, checksum: Option<Checksum>, mut progress_cb: F) -> Result<bool> where F: FnMut(u64) {
-        let mut downloaded = false;
+        if target_path.exists() {
+            return Ok(false)
+        }
 
-        if !target_path.exists() {
-            let mut response = self.http_client.get(url.as_str()).send().await?;
+        create_dirs(&target_path).await?;
 
-            if !response.status().is_success() {
-                return Err(ErrorKind::Download { url, status_code: response.status() })
-            }
+        let part_path = part_path(&target_path);
+
+        let mut resume_from = match tokio::fs::metadata(&part_path).await {
+            Ok(m) => m.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into())
+        };
+
+        let mut response = self.request_tarball(&url, resume_from).await?;
+
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE && resume_from > 0 {
+            // whatever we have is not a prefix of the tarball, start over
+            remove_if_exists(&part_path).await?;
+            resume_from = 0;
+            response = self.request_tarball(&url, resume_from).await?;
+        }
+
+        if !response.status().is_success() {
+            return Err(status_error(url, &response))
+        }
+
+        let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+        let resumable = resumed || accepts_ranges(response.headers());
+        let offset = if resumed { resume_from } else { 0 };
 
-            create_dirs(&target_path).await?;
+        // what we already have counts as well, as a retry takes back everything the failed attempt counted
+        self.inc_total_bytes(offset);
+        progress_cb(offset);
 
-            let mut output = BufWriter::new(File::create(&target_path).await?);
+        let expected_size = get_content_length(response.headers())?.map(|len| {
+            self.inc_total_bytes(len);
+            offset + len
+        });
 
-            if let Some(content_len) = response.headers().get(CONTENT_LENGTH) {
-                let size: u64 = content_len.to_str().expect("junk in content length").parse::<u64>()?;
-                self.progress.bytes.inc_total(size);
+        let mut hasher = checksum.as_ref().map(|c| c.create_hasher());
+
+        let file = if resumed {
+            if let Some(hasher) = &mut hasher {
+                hash_file(&part_path, hasher.as_mut()).await?;
             }
 
-            if let Some(expected_checksum) = checksum {
-                let mut hasher = expected_checksum.create_hasher();
+            OpenOptions::new().append(true).open(&part_path).await?
+        } else {
+            File::create(&part_path).await?
+        };
+
+        let mut output = BufWriter::new(file);
+        let mut size = offset;
+
+        let mut stall_detector = StallDetector::new(&self.opts);
 
-                while let Some(chunk) = response.chunk().await? {
-                    output.write_all(&chunk).await?;
+        let result: Result<()> = async {
+            while let Some(chunk) = response.chunk().await? {
+                stall_detector.check(&url, chunk.len())?;
+                output.write_all(&chunk).await?;
+
+                if let Some(hasher) = &mut hasher {
                     hasher.consume(&chunk);
-            
-                    progress_cb(chunk.len() as u64);
                 }
 
-                let checksum = hasher.compute();
+                size += chunk.len() as u64;
 
-                if expected_checksum != checksum {
-                    drop(output);
-                    tokio::fs::remove_file(&target_path).await?;
-                    return Err(ErrorKind::Checksum { 
-                        url, 
-                        expected: expected_checksum.to_string(), 
-                        hash: checksum.to_string() 
-                    })
-                }
-            } else {
-                while let Some(chunk) = response.chunk().await? {
-                    output.write_all(&chunk).await?;
-            
-                    progress_cb(chunk.len() as u64);
-                }
+                progress_cb(chunk.len() as u64);
+                stall_detector.pause(self.limiter.bytes(chunk.len())).await;
             }
 
-            output.flush().await?;
-            downloaded = true;
+            Ok(())
+        }.await;
+
+        output.flush().await?;
+        let file = output.into_inner();
+
+        // a partial download is only worth keeping if the server lets us continue it later
+        if let Err(e) = result {
+            if !resumable {
+                remove_if_exists(&part_path).await?;
+            }
+
+            return Err(e)
+        }
+
+        if let Some(expected) = expected_size && expected != size {
+            if size > expected || !resumable {
+                remove_if_exists(&part_path).await?;
+            }
+
+            return Err(ErrorKind::Size { url, expected, actual: size })
+        }
+
+        if let (Some(expected_checksum), Some(hasher)) = (checksum, hasher) {
+            let checksum = hasher.compute();
+
+            if expected_checksum != checksum {
+                remove_if_exists(&part_path).await?;
+
+                return Err(ErrorKind::Checksum { 
+                    url, 
+                    expected: expected_checksum.to_string(), 
+                    hash: checksum.to_string() 
+                })
+            }
         }
 
-        Ok(downloaded)
+        finish_atomic(&self.opts, file, &part_path, &target_path).await?;
+
+        Ok(true)
+    }
+
+    async fn request_tarball(&self, url: &str, resume_from: u64) -> Result<Response> {
+        let mut request = self.http_clients.for_url(url).get(url);
+
+        if resume_from > 0 {
+            request = request.header(RANGE, format!("bytes={resume_from}-"));
+        }
+
+        self.limiter.request().await;
+
+        self.send(request).await
     }
 }
 
-fn get_etag(headers: &HeaderMap) -> Option<&str> {
-    let Some(etag) = headers.get(ETAG).map(|v| v.to_str().unwrap()) else {
-        return None
+/// Metadata documents are stored in a file named after their etag, which the link points at.
+fn metadata_file_path(link_path: &Path, etag: Option<&str>) -> PathBuf {
+    match etag {
+        Some(etag) => link_path.parent().unwrap().join(etag),
+        None => link_path.to_path_buf()
+    }
+}
+
+fn status_error(url: String, response: &Response) -> ErrorKind {
+    ErrorKind::Download {
+        url,
+        status_code: response.status(),
+        retry_after: get_retry_after(response.headers())
+    }
+}
+
+fn get_content_length(headers: &HeaderMap) -> Result<Option<u64>> {
+    let Some(content_len) = headers.get(CONTENT_LENGTH) else {
+        return Ok(None)
     };
 
-    let etag = etag.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(&etag);
+    Ok(Some(content_len.to_str().expect("junk in content length").parse::<u64>()?))
+}
 
-    return Some(etag)
+fn accepts_ranges(headers: &HeaderMap) -> bool {
+    headers.get(ACCEPT_RANGES)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|unit| unit.trim().eq_ignore_ascii_case("bytes")))
 }
 
-#[derive(Clone)]
-pub struct Downloader {
-    sender: Sender<Download>,
-    _tasks: Arc<Vec<JoinHandle<()>>>,
-    progress: Progress
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+
+    PathBuf::from(part_path)
 }
 
-impl Default for Downloader {
-    fn default() -> Self {
-        let (sender, _) = bounded(1);
-        Self {
-            sender,
-            _tasks: Default::default(),
-            progress: Default::default()
+async fn hash_file(path: &Path, hasher: &mut dyn Hasher) -> Result<()> {
+    let mut file = File::open(path).await?;
+    let mut buf = vec![0u8; 64*1024];
+
+    loop {
+        match file.read(&mut buf).await? {
+            0 => return Ok(()),
+            len => hasher.consume(&buf[..len])
         }
     }
 }
 
-impl Downloader {
-    pub fn build(opts: &CliOpts, meta_cache: Arc<RwLock<MetaCache>>) -> Self {
-        let (sender, receiver) = bounded(1024);
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into())
+    }
+}
+
+fn get_etag(headers: &HeaderMap) -> Option<&str> {
+    let etag = headers.get(ETAG).map(|v| v.to_str().unwrap())?;
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
 
-        let mut tasks = Vec::with_capacity(opts.dl_threads as usize);
+    let etag = etag.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(etag);
+
+    Some(etag)
+}
+
+#[derive(Clone, Default)]
+pub struct Downloader {
+    scheduler: Arc<Scheduler>,
+    arrivals: Arc<std::sync::Mutex<Option<UnboundedReceiver<CompactString>>>>,
+    tasks: Arc<Vec<JoinHandle<()>>>,
+    progress: Progress,
+    shutdown: Shutdown,
+}
+
+impl Downloader {
+    pub fn build(opts: &CliOpts, meta_cache: Arc<MetaCache>, shutdown: Shutdown) -> Result<Self> {
+        let scheduler = Arc::new(Scheduler::default());
+        let (arrivals, arrivals_receiver) = unbounded_channel();
         let progress = Progress::new();
-        let http_client = reqwest::Client::new();
+        let http_clients = HttpClients::build(opts)?;
+        let retry_policy = Arc::new(RetryPolicy::new(opts));
+        let limiter = Arc::new(Limiter::new(opts));
+        let concurrency = Arc::new(Concurrency::new(opts));
 
         let task_opts = Arc::new(opts.to_owned());
 
-        for _ in 0..opts.dl_threads {
+        // in adaptive mode, there are enough tasks for the highest limit, with the ones above the current limit
+        // waiting for their turn
+        let task_count = concurrency.max_tasks();
+        let mut tasks = Vec::with_capacity(task_count);
+
+        for _ in 0..task_count {
             let dl_task = DownloadTask {
                 meta_cache: meta_cache.clone(),
                 opts: task_opts.clone(),
-                receiver: receiver.clone(),
+                scheduler: scheduler.clone(),
                 progress: progress.clone(),
-                http_client: http_client.clone(),
+                http_clients: http_clients.clone(),
+                retry_policy: retry_policy.clone(),
+                limiter: limiter.clone(),
+                concurrency: concurrency.clone(),
+                arrivals: arrivals.clone(),
+                shutdown: shutdown.clone(),
+                attempt_total_bytes: AtomicU64::new(0),
             };
 
             let mut buf = Vec::with_capacity(1024*1024);
 
             let handle = tokio::spawn(async move {
-                while let Ok(dl) = dl_task.receiver.recv().await {
+                loop {
+                    let (dl, permit) = dl_task.scheduler.pop(&dl_task.concurrency).await;
+
+                    // drain whatever is left in the queue without downloading it
+                    if dl_task.shutdown.is_triggered() {
+                        dl_task.progress.files.inc_skipped(1);
+                        dl_task.scheduler.finish(&dl);
+                        continue
+                    }
+
                     buf.clear();
-                    _ = dl_task.download_and_track(&mut buf, dl).await;
+                    _ = dl_task.download_and_track(&mut buf, dl, permit).await;
                 }
             });
 
             tasks.push(handle);
         }
 
-        Self {
-            sender,
-            _tasks: Arc::new(tasks),
-            progress
-        }
+        Ok(Self {
+            scheduler,
+            arrivals: Arc::new(std::sync::Mutex::new(Some(arrivals_receiver))),
+            tasks: Arc::new(tasks),
+            progress,
+            shutdown
+        })
     }
 
+    /// Queues a download, unless the same url was queued before.
     pub async fn queue(&self, download_entry: Download) -> Result<()> {
+        if !self.scheduler.claim(&download_entry) {
+            return Ok(())
+        }
+
         self.progress.files.inc_total(1);
 
-        self.sender.send(download_entry).await?;
+        if let Download::Tarball { .. } = download_entry {
+            self.progress.tarballs.inc_total(1);
+        }
+
+        self.scheduler.push(download_entry).await;
 
         Ok(())
     }
 
+    /// Hands out the names of packages whose metadata download finished, successful or not. There is only one
+    /// receiver, so this returns None after the first call.
+    pub fn metadata_arrivals(&self) -> Option<UnboundedReceiver<CompactString>> {
+        self.arrivals.lock().unwrap().take()
+    }
+
     pub fn progress(&self) -> Progress {
         self.progress.clone()
     }
+
+    /// Waits until every queued download is done and finishes the progress bar, unless shutting down.
+    pub async fn wait_for_completion(&self, progress_bar: &ProgressBar) -> Result<()> {
+        self.wait_until_drained(progress_bar).await?;
+        self.progress.wait_for_completion(progress_bar).await;
+
+        Ok(())
+    }
+
+    async fn wait_until_drained(&self, progress_bar: &ProgressBar) -> Result<()> {
+        while self.progress.files.remaining() > 0 {
+            if self.is_shutting_down() {
+                return Err(ErrorKind::Interrupted)
+            }
+
+            self.progress.update_for_files(progress_bar).await;
+            sleep(Duration::from_millis(100)).await
+        }
+
+        // queued downloads may have been dropped rather than completed
+        if self.is_shutting_down() {
+            return Err(ErrorKind::Interrupted)
+        }
+
+        Ok(())
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_triggered()
+    }
+
+    /// Waits for in-flight downloads to finish, up to the timeout, then stops all download tasks. Partial downloads
+    /// are left as temporary or .part files, to be cleaned up or resumed by the next run.
+    pub async fn shutdown(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+
+        while self.progress.files.remaining() > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(100)).await
+        }
+
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+    }
 }
 
 pub async fn create_dirs<P: AsRef<Path>>(path: P) -> Result<()> {
-    if let Some(parent_dir) = path.as_ref().parent() {
-        if !parent_dir.exists() {
-            tokio::fs::create_dir_all(parent_dir).await?;
-        }
+    if let Some(parent_dir) = path.as_ref().parent() && !parent_dir.exists() {
+        tokio::fs::create_dir_all(parent_dir).await?;
     }
 
     Ok(())
 }
 
+#[derive(Clone)]
 pub enum Download {
     Metadata {
         url: String,
         package: CompactString,
         target_path: PathBuf,
+        /// Where to copy from when the registry is the output folder of another mirror.
+        source: Option<PathBuf>,
     },
     Tarball {
         url: String,
         target_path: PathBuf,
         checksum: Option<Checksum>,
+        size: Option<u64>,
+        source: Option<PathBuf>,
     }
 }
 
 impl Download {
-    pub fn metadata(opts: &CliOpts, package: &str) -> Download {
-        let url_base = opts.registry_url.strip_suffix('/').unwrap_or(&opts.registry_url);
+    pub fn url(&self) -> &str {
+        match self {
+            Download::Metadata { url, .. } |
+            Download::Tarball { url, .. } => url,
+        }
+    }
+
+    pub fn target_path(&self) -> &Path {
+        match self {
+            Download::Metadata { target_path, .. } |
+            Download::Tarball { target_path, .. } => target_path,
+        }
+    }
+
+    /// The download of the metadata of a package, or nothing if the package name would point outside the mirror.
+    pub fn metadata(opts: &CliOpts, package: &str) -> Option<Download> {
+        let Some(package_path) = contained_path(package) else {
+            log(format!("skipping package {package}, its name is not a valid path"));
+            return None
+        };
+
+        let registry_url = registry_url_for(opts, package);
+        let url_base = registry_url.strip_suffix('/').unwrap_or(registry_url);
 
-        Download::Metadata {
+        let target_path = match opts.metadata_format.is_abbreviated() {
+            true => local_abbreviated_metadata_path(opts, package),
+            false => local_metadata_path(opts, package)
+        };
+
+        Some(Download::Metadata {
             package: package.to_compact_string(),
             url: format!("{}/{}", url_base, package),
-            target_path: local_metadata_path(opts, package)
-        }
+            target_path,
+            source: local_path(registry_url).map(|registry_dir| registry_dir.join(package_path)),
+        })
     }
 
-    pub fn tarball(opts: &CliOpts, package: &str, url: &TarballUrl) -> Download {
+    /// The download of a tarball, or nothing if the metadata points it somewhere it mustn't come from or go to.
+    /// Whether tarballs are copied from another mirror is decided by the configured registry alone, never by the
+    /// tarball url, and tarballs of a remote registry are only ever downloaded over http(s).
+    pub fn tarball(opts: &CliOpts, package: &str, url: &TarballUrl, checksum: Option<&Checksum>, size: Option<u64>) -> Option<Download> {
         let output_base = opts.output.strip_suffix('/').unwrap_or(&opts.output);
-        let url_base = opts.registry_url.strip_prefix('/').unwrap_or(&opts.registry_url);
+        let registry_url = registry_url_for(opts, package);
+        let url_base = registry_url.strip_suffix('/').unwrap_or(registry_url);
+        let registry_dir = local_path(registry_url);
 
         let url = match url {
             TarballUrl::Short(short) => {
                 format!("{url_base}/{package}/-/{short}")
             },
+            // another mirror keeps its tarballs where it would have downloaded them to, wherever the metadata says
+            // they came from
+            TarballUrl::Full(v) if registry_dir.is_some() => {
+                let name = v.split('/').next_back().unwrap();
+                format!("{url_base}/{package}/-/{name}")
+            },
             TarballUrl::Full(v) => v.to_string(),
         };
 
-        let target_path = if let Some(last_part) = url.strip_prefix(opts.registry_url.as_str()) {
-            let path_part = last_part.strip_prefix('/').unwrap_or(last_part);
-            format!("{output_base}/{path_part}")
-        } else {
-            let name = url.split('/').last().unwrap();
-            format!("{output_base}/{package}/-/{name}")
+        // only tarballs of the registry keep their path, a lookalike host shares a prefix with it but nothing else
+        let is_registry_tarball = registry_dir.is_some() || url_is_under(&url, registry_url);
+
+        let relative_path = match url.strip_prefix(registry_url) {
+            Some(last_part) if is_registry_tarball => last_part.strip_prefix('/').unwrap_or(last_part).to_string(),
+            _ => format!("{package}/-/{}", url.split('/').next_back().unwrap())
+        };
+
+        let is_http = Url::parse(&url).is_ok_and(|v| v.scheme() == "http" || v.scheme() == "https");
+
+        let relative_path = match contained_path(&relative_path) {
+            Some(relative_path) if registry_dir.is_some() || is_http => relative_path,
+            _ => {
+                log(format!("skipping tarball {url} of {package}, it's not a tarball of the registry"));
+                return None
+            }
         };
 
-        Download::Tarball {
+        Some(Download::Tarball {
+            source: registry_dir.map(|registry_dir| registry_dir.join(relative_path)),
+            target_path: Path::new(output_base).join(relative_path),
             url,
-            checksum: None,
-            target_path: PathBuf::from(target_path)
-        }
+            checksum: checksum.cloned(),
+            size
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn opts(args: &[&str]) -> CliOpts {
+        CliOpts::parse_from(["npmmirs", "-o", "/mirror"].iter().chain(args))
+    }
+
+    fn tarball(opts: &CliOpts, package: &str, url: &str) -> Option<Download> {
+        Download::tarball(opts, package, &TarballUrl::Full(url.to_string()), None, None)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn remote_registry_tarballs_are_downloaded() {
+        let opts = opts(&["-r", "https://registry.npmjs.org"]);
+        let dl = tarball(&opts, "@scope/x", "https://registry.npmjs.org/@scope/x/-/x-1.0.0.tgz").unwrap();
+
+        assert_eq!(dl.target_path(), Path::new("/mirror/@scope/x/-/x-1.0.0.tgz"));
+        assert!(matches!(dl, Download::Tarball { source: None, .. }));
+
+        let lookalike = tarball(&opts, "x", "https://registry.npmjs.org.evil.com/x/-/x-1.0.0.tgz").unwrap();
+        assert_eq!(lookalike.target_path(), Path::new("/mirror/x/-/x-1.0.0.tgz"));
+    }
+
+    #[test]
+    fn remote_registry_refuses_local_tarballs() {
+        let opts = opts(&["-r", "https://registry.npmjs.org"]);
+
+        assert!(tarball(&opts, "x", "/etc/shadow").is_none());
+        assert!(tarball(&opts, "x", "file:///etc/shadow").is_none());
+        assert!(tarball(&opts, "x", "https://registry.npmjs.org/../../etc/x.tgz").is_none());
+        assert!(Download::metadata(&opts, "../x").is_none());
+    }
+
+    #[test]
+    fn local_registry_copies_from_below_itself() {
+        let opts = opts(&["-r", "/upstream"]);
+        let dl = tarball(&opts, "x", "/etc/shadow").unwrap();
+
+        assert!(matches!(&dl, Download::Tarball { source: Some(source), .. } if source == Path::new("/upstream/x/-/shadow")));
+        assert!(tarball(&opts, "x", "https://registry.npmjs.org/x/-/..").is_none());
+        assert!(matches!(Download::metadata(&opts, "x"), Some(Download::Metadata { source: Some(source), .. }) if source == Path::new("/upstream/x")));
+    }
+}
@@ -1,7 +1,8 @@
+use std::{path::PathBuf, time::Duration};
+
 use reqwest::StatusCode;
 use thiserror::Error;
 
-use crate::downloader::Download;
 
 
 pub type Result<T> = std::result::Result<T, ErrorKind>;
@@ -17,28 +18,43 @@ pub enum NpmError {
     #[error("downloading packages failed: {}", .0)]
     Packages(ErrorKind),
 
+    #[error("saving or loading the run state failed: {}", .0)]
+    State(ErrorKind),
+
+    #[error("cleaning up temporary files failed: {}", .0)]
+    Cleanup(ErrorKind),
+
+    #[error("checking protected packages failed: {}", .0)]
+    Protection(ErrorKind),
+
 }
 
 #[derive(Error, Debug)]
 pub enum ErrorKind {
-    #[error("async channel send error: {}", .0)]
-    AsyncChannelSend(#[from]async_channel::SendError<Download>),
-
     #[error("failed downloading {}: {status_code}", .url)]
-    Download { url: String, status_code: StatusCode },
+    Download { url: String, status_code: StatusCode, retry_after: Option<Duration> },
 
-    #[error("")]
+    #[error("checksum mismatch for {url}: expected {expected}, got {hash}")]
     Checksum { url: String, expected: String, hash: String },
 
+    #[error("size mismatch for {url}: expected {expected} bytes, got {actual}")]
+    Size { url: String, expected: u64, actual: u64 },
+
     #[error("failed to parse as checksum: {value}")]
     IntoChecksum { value: String },
 
     #[error("failed to parse hex: {}", .0)]
     FromHex(#[from]hex::FromHexError),
 
+    #[error("failed to parse base64: {}", .0)]
+    Base64(#[from]base64::DecodeError),
+
     #[error("io error: {}", .0)]
     Io(#[from]std::io::Error),
 
+    #[error("unable to read {}: {error}", .path.display())]
+    ReadFile { path: PathBuf, error: std::io::Error },
+
     #[error("reqwest error: {}", .0)]
     Reqwest(#[from]reqwest::Error),
 
@@ -56,4 +72,78 @@ pub enum ErrorKind {
 
     #[error("semver parse error: {}", .0)]
     SemVer(#[from]nodejs_semver::SemverError),
-}
\ No newline at end of file
+
+    #[error("unsupported package idx format{}", .version.map(|v| format!(" version {v}")).unwrap_or_default())]
+    IdxFormat { version: Option<u16> },
+
+    #[error("download of {url} stalled, only {bytes} bytes received in {window:?}")]
+    Stalled { url: String, bytes: u64, window: Duration },
+
+    #[error("background task failed: {}", .0)]
+    Join(#[from]tokio::task::JoinError),
+
+    #[error("interrupted")]
+    Interrupted,
+
+    #[error("protected package {package} would be resolved from {origin}")]
+    ProtectedPackage { package: String, origin: String },
+
+    #[error("{count} protected packages have local metadata from outside the protected registry")]
+    ProtectedMetadata { count: usize },
+}
+
+impl NpmError {
+    pub fn is_interrupted(&self) -> bool {
+        match self {
+            NpmError::Dependencies(e) |
+            NpmError::ChildDependencies(e) |
+            NpmError::Packages(e) => matches!(e, ErrorKind::Interrupted),
+            _ => false
+        }
+    }
+}
+
+impl ErrorKind {
+    /// Whether the error is transient, meaning that another attempt might succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ErrorKind::Download { status_code, .. } =>
+                *status_code == StatusCode::REQUEST_TIMEOUT ||
+                *status_code == StatusCode::TOO_MANY_REQUESTS ||
+                status_code.is_server_error(),
+            ErrorKind::Reqwest(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() || e.is_decode(),
+            ErrorKind::Size { .. } |
+            ErrorKind::Stalled { .. } => true,
+            _ => false
+        }
+    }
+
+    /// Whether the registry couldn't be reached or failed on its end, as opposed to answering with an error.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            ErrorKind::Download { status_code, .. } => status_code.is_server_error(),
+            ErrorKind::Reqwest(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            ErrorKind::Stalled { .. } => true,
+            _ => false
+        }
+    }
+
+    /// Whether the error suggests the registry is overwhelmed or throttling us.
+    pub fn is_congestion(&self) -> bool {
+        match self {
+            ErrorKind::Download { status_code, .. } =>
+                *status_code == StatusCode::TOO_MANY_REQUESTS ||
+                *status_code == StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::Reqwest(e) => e.is_timeout() || e.is_connect(),
+            ErrorKind::Stalled { .. } => true,
+            _ => false
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ErrorKind::Download { retry_after, .. } => *retry_after,
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use std::{future::Future, path::{Path, PathBuf}, str::FromStr, sync::Arc, time::Duration};
+
+use ahash::HashMap;
+use clap::ValueEnum;
+use compact_str::{CompactString, ToCompactString};
+use tokio::time::Instant;
+use reqwest::{Certificate, Client, ClientBuilder, Identity, NoProxy, Proxy, Url};
+
+use crate::{error::ErrorKind, CliOpts};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum HttpVersion {
+    /// Prefer HTTP/2, falling back to HTTP/1.1 for registries that don't offer it
+    Auto,
+    /// Always use HTTP/1.1
+    Http1,
+    /// Always use HTTP/2, even without TLS
+    Http2,
+}
+
+/// Catches downloads that still receive data, just too little of it to ever finish, which no timeout would.
+pub struct StallDetector {
+    min_bytes: u64,
+    window: Duration,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl StallDetector {
+    pub fn new(opts: &CliOpts) -> Self {
+        Self {
+            min_bytes: (opts.stall_speed as f64 * opts.stall_window.as_secs_f64()) as u64,
+            window: opts.stall_window,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Records received bytes, failing if less than the minimum arrived over the last window.
+    pub fn check(&mut self, url: &str, len: usize) -> Result<(), ErrorKind> {
+        self.window_bytes += len as u64;
+
+        if self.window_start.elapsed() < self.window {
+            return Ok(())
+        }
+
+        if self.window_bytes < self.min_bytes {
+            return Err(ErrorKind::Stalled { url: url.to_string(), bytes: self.window_bytes, window: self.window })
+        }
+
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+
+        Ok(())
+    }
+
+    /// Waits for something other than the network, like the bandwidth limiter, without the wait counting towards
+    /// the window.
+    pub async fn pause<T>(&mut self, wait: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let result = wait.await;
+        self.window_start += start.elapsed();
+
+        result
+    }
+}
+
+/// TLS settings that only apply to connections to a single host, in the form
+/// `<host>[:<port>][,ca=<pem file>][,cert=<pem file>][,insecure]`.
+#[derive(Clone, Debug)]
+pub struct HostTls {
+    host: CompactString,
+    ca_bundles: Vec<PathBuf>,
+    client_cert: Option<PathBuf>,
+    insecure: bool,
+}
+
+impl FromStr for HostTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',').map(str::trim);
+
+        let host = match parts.next() {
+            // urls are matched by their host, which is always lowercase once parsed
+            Some(host) if !host.is_empty() => host.to_ascii_lowercase().to_compact_string(),
+            _ => return Err(format!("missing host in host tls setting: {s}"))
+        };
+
+        let mut host_tls = Self {
+            host,
+            ca_bundles: Vec::new(),
+            client_cert: None,
+            insecure: false
+        };
+
+        for part in parts {
+            match part.split_once('=') {
+                Some(("ca", path)) => host_tls.ca_bundles.push(PathBuf::from(path)),
+                Some(("cert", path)) => host_tls.client_cert = Some(PathBuf::from(path)),
+                None if part == "insecure" => host_tls.insecure = true,
+                _ => return Err(format!("unknown host tls setting: {part}"))
+            }
+        }
+
+        Ok(host_tls)
+    }
+}
+
+/// The http clients used for upstream connections. Hosts with their own TLS settings get a client of their own,
+/// everything else shares the default client.
+#[derive(Clone)]
+pub struct HttpClients {
+    default: Client,
+    per_host: Arc<HashMap<CompactString, Client>>,
+}
+
+impl HttpClients {
+    pub fn build(opts: &CliOpts) -> Result<Self, ErrorKind> {
+        let default = client_builder(opts)?
+            .build()?;
+
+        let mut per_host = HashMap::default();
+
+        for host_tls in &opts.host_tls {
+            let mut builder = client_builder(opts)?
+                .danger_accept_invalid_certs(host_tls.insecure);
+
+            for cert in read_ca_bundles(&host_tls.ca_bundles)? {
+                builder = builder.add_root_certificate(cert);
+            }
+
+            if let Some(client_cert) = &host_tls.client_cert {
+                builder = builder.identity(read_identity(client_cert)?);
+            }
+
+            per_host.insert(host_tls.host.clone(), builder.build()?);
+        }
+
+        Ok(Self {
+            default,
+            per_host: Arc::new(per_host)
+        })
+    }
+
+    pub fn for_url(&self, url: &str) -> &Client {
+        if self.per_host.is_empty() {
+            return &self.default
+        }
+
+        let Ok(url) = Url::parse(url) else {
+            return &self.default
+        };
+
+        let Some(host) = url.host_str() else {
+            return &self.default
+        };
+
+        // a default port is as good as an explicit one, `npm.acme.internal:443` covers `https://npm.acme.internal`
+        if let Some(port) = url.port_or_known_default() && let Some(client) = self.per_host.get(format!("{host}:{port}").as_str()) {
+            return client
+        }
+
+        self.per_host.get(host).unwrap_or(&self.default)
+    }
+}
+
+fn client_builder(opts: &CliOpts) -> Result<ClientBuilder, ErrorKind> {
+    let mut builder = Client::builder()
+        .user_agent(&opts.user_agent)
+        .connect_timeout(opts.connect_timeout)
+        .read_timeout(opts.read_timeout)
+        .pool_idle_timeout(opts.pool_idle_timeout);
+
+    if let Some(timeout) = opts.request_timeout {
+        builder = builder.timeout(timeout);
+    }
+
+    if let Some(max_idle) = opts.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+
+    builder = match opts.http_version {
+        // negotiated during the TLS handshake, so plain http stays on HTTP/1.1
+        HttpVersion::Auto => builder.http2_adaptive_window(true),
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge().http2_adaptive_window(true),
+    };
+
+    if let Some(proxy_url) = &opts.proxy {
+        let no_proxy = NoProxy::from_string(&opts.no_proxy.join(","));
+        builder = builder.proxy(Proxy::all(proxy_url)?.no_proxy(no_proxy));
+    }
+
+    for cert in read_ca_bundles(&opts.ca_bundle)? {
+        builder = builder.add_root_certificate(cert);
+    }
+
+    if let Some(client_cert) = &opts.client_cert {
+        builder = builder.identity(read_identity(client_cert)?);
+    }
+
+    Ok(builder)
+}
+
+fn read_ca_bundles(paths: &[PathBuf]) -> Result<Vec<Certificate>, ErrorKind> {
+    let mut certs = Vec::new();
+
+    for path in paths {
+        certs.extend(Certificate::from_pem_bundle(&read_file(path)?)?);
+    }
+
+    Ok(certs)
+}
+
+fn read_identity(path: &Path) -> Result<Identity, ErrorKind> {
+    Ok(Identity::from_pem(&read_file(path)?)?)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, ErrorKind> {
+    std::fs::read(path).map_err(|error| ErrorKind::ReadFile { path: path.to_path_buf(), error })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use clap::Parser;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::TcpListener, sync::Mutex};
+    use tokio_rustls::{rustls::{crypto::ring, pki_types::PrivateKeyDer, server::WebPkiClientVerifier, RootCertStore, ServerConfig}, TlsAcceptor};
+
+    use super::*;
+
+    fn opts(args: &[String]) -> CliOpts {
+        CliOpts::parse_from(std::iter::once("npmmirs".to_string()).chain(args.iter().cloned()))
+    }
+
+    /// A local HTTPS stand-in for a registry requiring mutual TLS, with its own CA that issued both the server and
+    /// the client certificate.
+    struct StandIn {
+        dir: PathBuf,
+        port: u16,
+        ca_path: PathBuf,
+        client_cert_path: PathBuf,
+    }
+
+    impl StandIn {
+        async fn start(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("npmmirs-http-test-{}-{name}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+
+            let server_key = KeyPair::generate().unwrap();
+            let server = CertificateParams::new(vec!["localhost".to_string()]).unwrap()
+                .signed_by(&server_key, &ca, &ca_key).unwrap();
+
+            let client_key = KeyPair::generate().unwrap();
+            let client = CertificateParams::new(vec!["npmmirs".to_string()]).unwrap()
+                .signed_by(&client_key, &ca, &ca_key).unwrap();
+
+            let ca_path = dir.join("ca.pem");
+            let client_cert_path = dir.join("client.pem");
+
+            std::fs::write(&ca_path, ca.pem()).unwrap();
+            std::fs::write(&client_cert_path, format!("{}{}", client.pem(), client_key.serialize_pem())).unwrap();
+
+            let provider = Arc::new(ring::default_provider());
+
+            let mut roots = RootCertStore::empty();
+            roots.add(ca.der().clone()).unwrap();
+
+            let client_verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .unwrap();
+
+            let config = ServerConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions().unwrap()
+                .with_client_cert_verifier(client_verifier)
+                .with_single_cert(vec![server.der().clone()], PrivateKeyDer::Pkcs8(server_key.serialize_der().into()))
+                .unwrap();
+
+            let acceptor = TlsAcceptor::from(Arc::new(config));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let acceptor = acceptor.clone();
+
+                    tokio::spawn(async move {
+                        if let Ok(mut stream) = acceptor.accept(stream).await {
+                            respond(&mut stream, "registry").await;
+                        }
+                    });
+                }
+            });
+
+            Self {
+                dir,
+                port,
+                ca_path,
+                client_cert_path,
+            }
+        }
+
+        fn url(&self) -> String {
+            format!("https://localhost:{}/pkg", self.port)
+        }
+    }
+
+    impl Drop for StandIn {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Reads a request head and answers it with a fixed body.
+    async fn respond<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, body: &str) -> String {
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+
+        while !head.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(len) => head.extend_from_slice(&buf[..len]),
+            }
+        }
+
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.shutdown().await;
+
+        String::from_utf8_lossy(&head).lines().next().unwrap_or_default().to_string()
+    }
+
+    /// A plain http server answering every request with the body, recording the request lines it received.
+    async fn plain_server(body: &'static str) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request_line = respond(&mut stream, body).await;
+                recorded.lock().await.push(request_line);
+            }
+        });
+
+        (port, requests)
+    }
+
+    async fn get(clients: &HttpClients, url: &str) -> Result<String, reqwest::Error> {
+        clients.for_url(url).get(url).send().await?.text().await
+    }
+
+    #[tokio::test]
+    async fn stall_detection_ignores_time_spent_paused() {
+        let opts = opts(&["--stall-speed".into(), "1k".into(), "--stall-window".into(), "50ms".into()]);
+
+        let mut paused = StallDetector::new(&opts);
+        paused.pause(tokio::time::sleep(Duration::from_millis(100))).await;
+        assert!(paused.check("https://registry.npmjs.org/x", 10).is_ok());
+
+        let mut waited = StallDetector::new(&opts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(waited.check("https://registry.npmjs.org/x", 10), Err(ErrorKind::Stalled { .. })));
+    }
+
+    #[tokio::test]
+    async fn host_tls_applies_to_its_host_only() {
+        let stand_in = StandIn::start("host").await;
+
+        let clients = HttpClients::build(&opts(&[
+            "--host-tls".into(),
+            format!("localhost:{},ca={},cert={}", stand_in.port, stand_in.ca_path.display(), stand_in.client_cert_path.display()),
+        ])).unwrap();
+
+        assert_eq!(get(&clients, &stand_in.url()).await.unwrap(), "registry");
+
+        let clients = HttpClients::build(&opts(&[
+            "--host-tls".into(),
+            format!("localhost:1,ca={},cert={}", stand_in.ca_path.display(), stand_in.client_cert_path.display()),
+        ])).unwrap();
+
+        assert!(get(&clients, &stand_in.url()).await.is_err(), "settings for another port must not apply");
+    }
+
+    #[tokio::test]
+    async fn host_tls_matches_hosts_case_insensitively() {
+        let stand_in = StandIn::start("case").await;
+
+        let clients = HttpClients::build(&opts(&[
+            "--host-tls".into(),
+            format!("LocalHost,ca={},cert={}", stand_in.ca_path.display(), stand_in.client_cert_path.display()),
+        ])).unwrap();
+
+        assert_eq!(get(&clients, &stand_in.url()).await.unwrap(), "registry");
+    }
+
+    #[test]
+    fn host_tls_matches_default_ports() {
+        let clients = HttpClients::build(&opts(&["--host-tls".into(), "npm.acme.internal:443,insecure".into()])).unwrap();
+        let host_client = clients.per_host.get("npm.acme.internal:443").unwrap();
+
+        assert!(std::ptr::eq(clients.for_url("https://npm.acme.internal/x"), host_client));
+        assert!(std::ptr::eq(clients.for_url("https://npm.acme.internal:443/x"), host_client));
+        assert!(std::ptr::eq(clients.for_url("https://npm.acme.internal:8443/x"), &clients.default));
+        assert!(std::ptr::eq(clients.for_url("http://npm.acme.internal/x"), &clients.default));
+    }
+
+    #[tokio::test]
+    async fn client_cert_is_required_by_stand_in() {
+        let stand_in = StandIn::start("no-cert").await;
+
+        let clients = HttpClients::build(&opts(&[
+            "--host-tls".into(),
+            format!("localhost,ca={}", stand_in.ca_path.display()),
+        ])).unwrap();
+
+        assert!(get(&clients, &stand_in.url()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn global_ca_bundle_and_client_cert() {
+        let stand_in = StandIn::start("global").await;
+
+        let clients = HttpClients::build(&opts(&[])).unwrap();
+
+        assert!(get(&clients, &stand_in.url()).await.is_err(), "the stand-in CA is not trusted by default");
+
+        let clients = HttpClients::build(&opts(&[
+            "--ca-bundle".into(), stand_in.ca_path.display().to_string(),
+            "--client-cert".into(), stand_in.client_cert_path.display().to_string(),
+        ])).unwrap();
+
+        assert_eq!(get(&clients, &stand_in.url()).await.unwrap(), "registry");
+    }
+
+    #[tokio::test]
+    async fn proxy_skips_no_proxy_hosts() {
+        let (proxy_port, proxied) = plain_server("proxy").await;
+        let (direct_port, _) = plain_server("direct").await;
+
+        let clients = HttpClients::build(&opts(&[
+            "--proxy".into(), format!("http://127.0.0.1:{proxy_port}"),
+            "--no-proxy".into(), "localhost".into(),
+        ])).unwrap();
+
+        assert_eq!(get(&clients, "http://registry.invalid/pkg").await.unwrap(), "proxy");
+        assert_eq!(get(&clients, &format!("http://localhost:{direct_port}/pkg")).await.unwrap(), "direct");
+
+        assert_eq!(*proxied.lock().await, vec!["GET http://registry.invalid/pkg HTTP/1.1".to_string()]);
+    }
+}
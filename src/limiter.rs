@@ -0,0 +1,235 @@
+use std::{str::FromStr, time::{Duration, Instant}};
+
+use chrono::NaiveTime;
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::CliOpts;
+
+/// Limits the request rate and bandwidth of all downloads combined.
+pub struct Limiter {
+    requests: Option<TokenBucket>,
+    bandwidth: Option<TokenBucket>,
+    schedule: Vec<TimeWindow>,
+}
+
+impl Limiter {
+    pub fn new(opts: &CliOpts) -> Self {
+        Self {
+            requests: opts.max_requests_per_second.map(TokenBucket::new),
+            bandwidth: opts.max_bytes_per_second.map(|v| TokenBucket::new(v as f64)),
+            schedule: opts.limit_schedule.clone(),
+        }
+    }
+
+    /// Waits until another request may be sent.
+    pub async fn request(&self) {
+        if let Some(requests) = &self.requests && self.is_active() {
+            requests.take(1.0).await
+        }
+    }
+
+    /// Accounts for received bytes, waiting if the bandwidth cap has been exceeded.
+    pub async fn bytes(&self, count: usize) {
+        if let Some(bandwidth) = &self.bandwidth && self.is_active() {
+            bandwidth.take(count as f64).await
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        if self.schedule.is_empty() {
+            return true
+        }
+
+        let now = chrono::Local::now().time();
+
+        self.schedule.iter().any(|w| w.contains(now))
+    }
+}
+
+/// A token bucket holding at most one second worth of tokens. Taking more tokens than available puts the bucket
+/// in debt, which the caller waits out.
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    async fn take(&self, amount: f64) {
+        let wait = {
+            let mut state = self.state.lock().await;
+            let (tokens, last) = &mut *state;
+
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+            *last = now;
+            *tokens -= amount;
+
+            if *tokens >= 0.0 {
+                return
+            }
+
+            Duration::from_secs_f64(-*tokens / self.rate)
+        };
+
+        sleep(wait).await
+    }
+}
+
+/// A time of day window, such as `08:00-18:00`. Windows ending before they start wrap around midnight.
+#[derive(Clone, Debug)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| NaiveTime::parse_from_str(v.trim(), "%H:%M")
+            .map_err(|e| format!("invalid time {v} in window {s}: {e}"));
+
+        let Some((start, end)) = s.split_once('-') else {
+            return Err(format!("time window must be in the form HH:MM-HH:MM: {s}"))
+        };
+
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+/// Parses a byte count with an optional binary unit suffix, such as `512k` or `10MiB`.
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    match parse_byte_count(s)? {
+        0 => Err(format!("byte count must be positive: {}", s.trim())),
+        bytes => Ok(bytes)
+    }
+}
+
+/// Like [`parse_bytes`], but also accepts 0 in any unit, for settings where 0 turns something off.
+pub fn parse_byte_count(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let lowercase = s.to_ascii_lowercase();
+    let unit_start = lowercase.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(lowercase.len());
+
+    let (value, unit) = lowercase.split_at(unit_start);
+
+    let multiplier: u64 = match unit.trim_end_matches("ib").trim_end_matches('b') {
+        "" => 1,
+        "k" => 1024,
+        "m" => 1024 * 1024,
+        "g" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown unit in {s}"))
+    };
+
+    let value = match value.trim().parse::<f64>() {
+        Ok(v) if v >= 0.0 && v.is_finite() => v,
+        Ok(_) => return Err(format!("byte count must not be negative: {s}")),
+        Err(e) => return Err(format!("invalid number {s}: {e}"))
+    };
+
+    // fractions of a byte still count as one, unless it's 0
+    match (value * multiplier as f64) as u64 {
+        0 if value > 0.0 => Ok(1),
+        bytes => Ok(bytes)
+    }
+}
+
+/// Parses a positive rate, such as requests per second.
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        Ok(_) => Err(format!("rate must be positive: {s}")),
+        Err(e) => Err(format!("invalid number {s}: {e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(v: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(v, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn time_window_within_a_day() {
+        let window: TimeWindow = "08:00-18:00".parse().unwrap();
+
+        assert!(window.contains(time("08:00")));
+        assert!(window.contains(time("17:59")));
+        assert!(!window.contains(time("18:00")));
+        assert!(!window.contains(time("03:00")));
+    }
+
+    #[test]
+    fn time_window_wrapping_around_midnight() {
+        let window: TimeWindow = "22:00-06:00".parse().unwrap();
+
+        assert!(window.contains(time("22:00")));
+        assert!(window.contains(time("23:59")));
+        assert!(window.contains(time("00:00")));
+        assert!(window.contains(time("05:59")));
+        assert!(!window.contains(time("06:00")));
+        assert!(!window.contains(time("12:00")));
+    }
+
+    #[test]
+    fn invalid_time_windows() {
+        assert!("08:00".parse::<TimeWindow>().is_err());
+        assert!("8am-6pm".parse::<TimeWindow>().is_err());
+        assert!("08:00-25:00".parse::<TimeWindow>().is_err());
+    }
+
+    #[test]
+    fn byte_units() {
+        assert_eq!(parse_bytes("512"), Ok(512));
+        assert_eq!(parse_bytes("512b"), Ok(512));
+        assert_eq!(parse_bytes("1k"), Ok(1024));
+        assert_eq!(parse_bytes("1.5K"), Ok(1536));
+        assert_eq!(parse_bytes("10MiB"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_bytes(" 2 GB "), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_bytes("0.1"), Ok(1));
+        assert!(parse_bytes("1t").is_err());
+        assert!(parse_bytes("-1k").is_err());
+        assert!(parse_bytes("k").is_err());
+    }
+
+    #[test]
+    fn zero_bytes_only_where_allowed() {
+        assert!(parse_bytes("0").is_err());
+        assert!(parse_bytes("0k").is_err());
+        assert_eq!(parse_byte_count("0"), Ok(0));
+        assert_eq!(parse_byte_count("0M"), Ok(0));
+        assert_eq!(parse_byte_count("1k"), Ok(1024));
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("10"), Ok(10.0));
+        assert_eq!(parse_rate(" 0.5 "), Ok(0.5));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("inf").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+}
@@ -1,42 +1,216 @@
-use ahash::HashMap;
+use std::{fs::File, sync::{Arc, Mutex}};
+
+use ahash::HashSet;
 use compact_str::{CompactString, ToCompactString};
-use tokio::io::AsyncReadExt;
+use lru::LruCache;
+use memmap2::Mmap;
 
-use crate::metadata::package_index::PackageIndex;
+use crate::{log, metadata::{local_metadata_idx_path, package_index::{decode_package_idx, PackageIndex}}, CliOpts};
 
-#[derive(Default)]
+/// Holds the package idx of every package seen during a run. Compressed idx data stays in memory up to a budget,
+/// beyond that the least recently used entries are dropped and served from their idx files on disk instead. The
+/// most recently decoded indexes are kept as well, up to a budget of their own, so looking up the same package
+/// again skips decoding.
 pub struct MetaCache {
-    pos_map: HashMap<CompactString, (usize, usize)>,
-    data: Vec<u8>
+    opts: Arc<CliOpts>,
+    budget: usize,
+    state: Mutex<State>,
+    decoded: Mutex<Decoded>,
+}
+
+struct State {
+    resident_bytes: usize,
+    resident: LruCache<CompactString, Arc<[u8]>>,
+    /// Packages whose idx data was evicted, and is read from disk when needed.
+    cold: HashSet<CompactString>,
+}
+
+struct Decoded {
+    budget: usize,
+    bytes: usize,
+    entries: LruCache<CompactString, (Arc<PackageIndex>, usize)>,
+}
+
+impl Decoded {
+    fn put(&mut self, package: CompactString, idx: Arc<PackageIndex>) {
+        let size = idx.decoded_size();
+
+        self.remove(&package);
+        self.bytes += size;
+        self.entries.put(package, (idx, size));
+
+        while self.bytes > self.budget && let Some((_, (_, size))) = self.entries.pop_lru() {
+            self.bytes -= size;
+        }
+    }
+
+    fn remove(&mut self, package: &str) {
+        if let Some((_, size)) = self.entries.pop(package) {
+            self.bytes -= size;
+        }
+    }
+}
+
+enum Source {
+    Resident(Arc<[u8]>),
+    Cold,
 }
 
 impl MetaCache {
-    pub async fn get(&self, buf: &mut Vec<u8>, package: &str) -> Option<PackageIndex> {
-        let &(pos, len) = self.pos_map.get(package)?;
+    pub fn new(opts: &CliOpts) -> Self {
+        Self {
+            opts: Arc::new(opts.clone()),
+            budget: opts.meta_cache_size as usize,
+            state: Mutex::new(State {
+                resident_bytes: 0,
+                resident: LruCache::unbounded(),
+                cold: HashSet::default(),
+            }),
+            decoded: Mutex::new(Decoded {
+                budget: opts.decoded_idx_cache as usize,
+                bytes: 0,
+                entries: LruCache::unbounded(),
+            }),
+        }
+    }
+
+    pub fn get(&self, buf: &mut Vec<u8>, package: &str) -> Option<Arc<PackageIndex>> {
+        if let Some((idx, _)) = self.decoded.lock().unwrap().entries.get(package) {
+            return Some(idx.clone())
+        }
+
+        // decoding happens outside the lock, so that parallel expansions don't wait on each other
+        let source = {
+            let mut state = self.state.lock().unwrap();
+
+            if let Some(data) = state.resident.get(package) {
+                Source::Resident(data.clone())
+            } else if state.cold.contains(package) {
+                Source::Cold
+            } else {
+                return None
+            }
+        };
+
+        let idx = match &source {
+            Source::Resident(data) => decode_package_idx(buf, data).expect("package idx in cache is broken"),
+            Source::Cold => self.read_cold(buf, package)?
+        };
+
+        let idx = Arc::new(idx);
+
+        // the package may have been replaced while decoding, which mustn't bring back what it was replaced with
+        let state = self.state.lock().unwrap();
 
-        let mut compressed = &self.data[pos..pos+len];
+        let is_current = match &source {
+            Source::Resident(data) => state.resident.peek(package).is_some_and(|current| Arc::ptr_eq(current, data)),
+            Source::Cold => state.cold.contains(package)
+        };
 
-        let uncompressed_len: u64 = compressed.read_u64().await.unwrap();
+        if is_current {
+            self.decoded.lock().unwrap().put(package.to_compact_string(), idx.clone());
+        }
+
+        Some(idx)
+    }
 
-        buf.resize(uncompressed_len as usize, 0u8);
+    /// Decodes an evicted entry straight from its mapped idx file.
+    fn read_cold(&self, buf: &mut Vec<u8>, package: &str) -> Option<PackageIndex> {
+        let path = local_metadata_idx_path(&self.opts, package);
 
-        zstd::stream::copy_decode(&compressed[..], &mut buf[..]).expect("data is wrong :(");
+        let result = File::open(&path)
+            // SAFETY: idx files are only ever replaced by renaming a new file into place, never written in place,
+            // so the mapped file doesn't change underneath us
+            .and_then(|file| unsafe { Mmap::map(&file) })
+            .map_err(|e| e.into())
+            .and_then(|data| decode_package_idx(buf, &data));
 
-        Some(bitcode::deserialize(&buf[..]).expect("bitcode deserialization failed from cache"))
+        match result {
+            Ok(idx) => Some(idx),
+            Err(e) => {
+                log(format!("unable to read evicted idx of {package} from {}: {e}", path.display()));
+                None
+            }
+        }
     }
 
-    pub fn insert(&mut self, package: &str, data: &[u8]) -> bool {
-        if self.pos_map.contains_key(package) {
-            return false
+    /// Caches the idx data of a package, replacing what was cached for it before.
+    pub fn insert(&self, package: &str, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(old) = state.resident.pop(package) {
+            state.resident_bytes -= old.len();
         }
 
-        let len = data.len();
-        let pos = self.data.len();
+        state.cold.remove(package);
+        self.decoded.lock().unwrap().remove(package);
 
-        self.data.extend_from_slice(data);
+        state.resident_bytes += data.len();
+        state.resident.put(package.to_compact_string(), Arc::from(data));
 
-        self.pos_map.insert(package.to_compact_string(), (pos, len));
+        // the evicted idx files are always on disk by now, as idx data is only cached after being written or
+        // when read from disk
+        while state.resident_bytes > self.budget && let Some((package, data)) = state.resident.pop_lru() {
+            state.resident_bytes -= data.len();
+            state.cold.insert(package);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::metadata::package_index::{write_package_idx, Validators};
 
-        true
+    use super::*;
+
+    fn idx(versions: &[&str]) -> PackageIndex {
+        PackageIndex {
+            versions: versions.iter().map(|v| v.parse().unwrap()).collect(),
+            tarballs: versions.iter().map(|_| None).collect(),
+            checksums: versions.iter().map(|_| None).collect(),
+            sizes: versions.iter().map(|_| None).collect(),
+            deps: versions.iter().map(|_| Vec::new()).collect(),
+            ..Default::default()
+        }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn insert_replaces_cached_and_decoded_entries() {
+        let dir = std::env::temp_dir().join(format!("npmmirs-meta-cache-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("x")).unwrap();
+
+        let output = dir.to_str().unwrap();
+        let opts = CliOpts::parse_from(["npmmirs", "-o", output, "--meta-cache-size", "1", "--decoded-idx-cache", "1M"]);
+        let meta_cache = MetaCache::new(&opts);
+        let target_path = dir.join("x/index.json");
+        let mut buf = Vec::new();
+
+        write_package_idx(&opts, &mut buf, "x", &target_path, idx(&["1.0.0"]), &Validators::default(), &meta_cache).await.unwrap();
+        assert_eq!(meta_cache.get(&mut buf, "x").unwrap().versions.len(), 1);
+
+        // evicted by the tiny budget, and replaced while cold
+        write_package_idx(&opts, &mut buf, "x", &target_path, idx(&["1.0.0", "1.1.0"]), &Validators::default(), &meta_cache).await.unwrap();
+        assert_eq!(meta_cache.get(&mut buf, "x").unwrap().versions.len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decoded_indexes_stay_within_budget() {
+        let small = Arc::new(idx(&["1.0.0"]));
+        let mut decoded = Decoded { budget: small.decoded_size() * 2, bytes: 0, entries: LruCache::unbounded() };
+
+        for package in ["a", "b", "c"] {
+            decoded.put(package.into(), small.clone());
+        }
+
+        assert_eq!(decoded.entries.len(), 2);
+        assert!(!decoded.entries.contains("a"));
+
+        decoded.put("b".into(), Arc::new(idx(&["1.0.0", "2.0.0"])));
+        assert!(decoded.bytes <= decoded.budget);
+        assert!(decoded.entries.contains("b"));
+    }
+}
@@ -1,19 +1,54 @@
 use std::path::PathBuf;
 
+use clap::ValueEnum;
+
 use crate::CliOpts;
 
 pub mod manifest;
 pub mod package_index;
 pub mod sparse_metadata;
 
+/// Asks for the abbreviated ("corgi") metadata document, which only holds what's needed for installing.
+pub const ABBREVIATED_ACCEPT: &str = "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*";
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum MetadataFormat {
+    /// Resolve using the full metadata documents, stored as index.json
+    Full,
+    /// Resolve using the abbreviated metadata documents, stored as index.corgi.json
+    Abbreviated,
+    /// Resolve using the abbreviated metadata documents, but store the full ones as well
+    Both,
+}
+
+impl MetadataFormat {
+    /// Whether resolving uses the abbreviated metadata documents.
+    pub fn is_abbreviated(self) -> bool {
+        self != MetadataFormat::Full
+    }
+}
+
 pub fn local_metadata_path(opts: &CliOpts, package: &str) -> PathBuf {
     let output_base = opts.output.strip_suffix('/').unwrap_or(&opts.output);
 
     PathBuf::from(format!("{output_base}/{package}/index.json"))
 }
 
+pub fn local_abbreviated_metadata_path(opts: &CliOpts, package: &str) -> PathBuf {
+    let output_base = opts.output.strip_suffix('/').unwrap_or(&opts.output);
+
+    PathBuf::from(format!("{output_base}/{package}/index.corgi.json"))
+}
+
 pub fn local_metadata_idx_path(opts: &CliOpts, package: &str) -> PathBuf {
     let output_base = opts.output.strip_suffix('/').unwrap_or(&opts.output);
 
     PathBuf::from(format!("{output_base}/{package}/index.json.idx"))
-}
\ No newline at end of file
+}
+
+/// Holds the unix timestamp of when the metadata of a package was last checked with the registry.
+pub fn local_metadata_checked_path(opts: &CliOpts, package: &str) -> PathBuf {
+    let output_base = opts.output.strip_suffix('/').unwrap_or(&opts.output);
+
+    PathBuf::from(format!("{output_base}/{package}/index.json.checked"))
+}
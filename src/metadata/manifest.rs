@@ -1,9 +1,10 @@
 use ahash::HashMap;
-use nodejs_semver::Range;
+use compact_str::CompactString;
 use serde::{Deserialize, Serialize};
 
+use super::sparse_metadata::DepVersion;
+
 #[derive(Serialize, Deserialize)]
 pub struct Manifest {
-    pub dependencies: HashMap<String, Range>,
+    pub dependencies: HashMap<CompactString, DepVersion>,
 }
-
@@ -3,11 +3,11 @@ use std::{collections::BTreeMap, path::Path};
 use compact_str::{CompactString, ToCompactString};
 use nodejs_semver::{Range, Version};
 use serde::{Deserialize, Serialize};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, sync::RwLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 
-use crate::{error::ErrorKind, meta_cache::MetaCache, CliOpts};
+use crate::{atomic_file::write_atomic, checksum::Checksum, error::ErrorKind, meta_cache::MetaCache, protection::registry_url_for, CliOpts};
 
-use super::{local_metadata_idx_path, sparse_metadata::{DepVersion, SparseMetadata, SubDep, VersionInfo}};
+use super::{local_metadata_idx_path, sparse_metadata::{DepVersion, SparseMetadata, SubDep, SubTag, VersionInfo}};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum IdxDepVersion {
@@ -15,15 +15,17 @@ pub enum IdxDepVersion {
     Range(Range),
     SubDep(SubDep),
     Other(CompactString),
+    SubTag(SubTag),
 }
 
 impl From<DepVersion> for IdxDepVersion {
     fn from(value: DepVersion) -> Self {
         match value {
-            DepVersion::Tag(s) => IdxDepVersion::Tag(s.into()),
+            DepVersion::Tag(s) => IdxDepVersion::Tag(s),
             DepVersion::Range(range) => IdxDepVersion::Range(range),
             DepVersion::SubDep(sub_dep) => IdxDepVersion::SubDep(sub_dep),
-            DepVersion::Other(other) => IdxDepVersion::Other(other.into()),
+            DepVersion::Other(other) => IdxDepVersion::Other(other),
+            DepVersion::SubTag(sub_tag) => IdxDepVersion::SubTag(sub_tag),
         }
     }
 }
@@ -40,11 +42,28 @@ pub enum TarballUrl {
     Full(String)
 }
 
+/// Written at the start of every idx file, followed by the format version. Idx files with a different format
+/// version are rebuilt from the registry.
+const IDX_MAGIC: &[u8; 6] = b"NPMIDX";
+const IDX_VERSION: u16 = 6;
+const IDX_HEADER_LEN: usize = IDX_MAGIC.len() + size_of::<u16>();
+
+/// The validators of the metadata document an idx was built from. They are stored uncompressed after the idx
+/// header, each prefixed with its length, so they can be read without decoding the idx.
+#[derive(Debug, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct PackageIndex {
     pub dist_tags: BTreeMap<String, usize>,
     pub versions: Vec<Version>,
     pub tarballs: Vec<Option<TarballUrl>>,
+    pub checksums: Vec<Option<Checksum>>,
+    /// Unpacked sizes of the tarballs, which the download scheduler uses to get small tarballs done first.
+    pub sizes: Vec<Option<u64>>,
     pub deps: Vec<Vec<IdxDep>>,
 }
 
@@ -54,13 +73,20 @@ impl PackageIndex {
             return Default::default()
         };
 
+        let registry_url = registry_url_for(opts, &value.name);
+
         let mut versions = Vec::with_capacity(version_map.len());
         let mut tarballs = Vec::with_capacity(version_map.len());
+        let mut checksums = Vec::with_capacity(version_map.len());
+        let mut sizes = Vec::with_capacity(version_map.len());
         let mut deps = Vec::with_capacity(version_map.len());
 
+        // the map hands out the versions sorted, as lookups expect them to be
         for (version, info) in version_map {
             versions.push(version);
-            tarballs.push(info.as_ref().map(|v| strip_path(&v.dist.tarball, &value.name, &opts.registry_url)));
+            tarballs.push(info.as_ref().map(|v| strip_path(&v.dist.tarball, &value.name, registry_url)));
+            checksums.push(info.as_ref().and_then(|v| Checksum::from_dist(v.dist.integrity.as_deref(), v.dist.shasum.as_deref())));
+            sizes.push(info.as_ref().and_then(|v| v.dist.unpacked_size));
             
             let mut v_deps = Vec::with_capacity(
                 info.as_ref().and_then(|v| v.dependencies.as_ref().map(|iv| iv.len())).unwrap_or(0) +
@@ -78,25 +104,19 @@ impl PackageIndex {
                 } = v;
 
                 if let Some(deps) = dependencies {
-                    v_deps.extend(deps.into_iter().map(|(package, range)| IdxDep { package: package.into(), range: range.into() }));
+                    v_deps.extend(deps.into_iter().map(|(package, range)| IdxDep { package, range: range.into() }));
                 }
 
-                if !opts.no_dev_deps {
-                    if let Some(deps) = dev_dependencies {
-                        v_deps.extend(deps.into_iter().map(|(package, range)| IdxDep { package: package.into(), range: range.into() }));
-                    }
+                if !opts.no_dev_deps && let Some(deps) = dev_dependencies {
+                    v_deps.extend(deps.into_iter().map(|(package, range)| IdxDep { package, range: range.into() }));
                 }
 
-                if !opts.no_optional_deps {
-                    if let Some(deps) = optional_dependencies {
-                        v_deps.extend(deps.into_iter().map(|(package, range)| IdxDep { package: package.into(), range: range.into() }));
-                    }
+                if !opts.no_optional_deps && let Some(deps) = optional_dependencies {
+                    v_deps.extend(deps.into_iter().map(|(package, range)| IdxDep { package, range: range.into() }));
                 }
 
-                if !opts.no_peer_deps {
-                    if let Some(deps) = peer_dependencies {
-                        v_deps.extend(deps.into_iter().map(|(package, range)| IdxDep { package: package.into(), range: range.into() }));
-                    }
+                if !opts.no_peer_deps && let Some(deps) = peer_dependencies {
+                    v_deps.extend(deps.into_iter().map(|(package, range)| IdxDep { package, range: range.into() }));
                 }
             }
 
@@ -106,13 +126,15 @@ impl PackageIndex {
         let mut idx = Self {
             versions,
             tarballs,
+            checksums,
+            sizes,
             deps,
             ..Default::default()
         };
     
         if let Some(dist_tags) = value.dist_tags {
             idx.dist_tags = dist_tags.into_iter()
-                .map(|(t, v)| (t, idx.pos_by_version(&v)))
+                .map(|(t, v)| (t.into(), idx.pos_by_version(&v)))
                 .filter(|(_, v)| v.is_some())
                 .map(|(t, v)| (t, v.unwrap()))
                 .collect();
@@ -122,10 +144,20 @@ impl PackageIndex {
     }
 
     pub fn tarball_by_version(&self, version: &Version) -> Option<&TarballUrl>{
-        let (pos, _) = self.versions.iter().enumerate()
-            .find(|(_, v)| *v == version)?;
+        self.pos_by_version(version)
+            .and_then(|pos| self.tarball_by_pos(pos))
+    }
+
+    pub fn checksum_by_version(&self, version: &Version) -> Option<&Checksum> {
+        self.pos_by_version(version)
+            .and_then(|pos| self.checksums.get(pos))
+            .and_then(|v| v.as_ref())
+    }
 
-        self.tarball_by_pos(pos)
+    pub fn size_by_version(&self, version: &Version) -> Option<u64> {
+        self.pos_by_version(version)
+            .and_then(|pos| self.sizes.get(pos))
+            .and_then(|v| *v)
     }
 
     pub fn version_by_tag(&self, tag: &str) -> Option<&Version> {
@@ -133,10 +165,59 @@ impl PackageIndex {
             .and_then(|pos| self.versions.get(*pos))
     }
 
+    /// Roughly how much memory the decoded index takes up. Only the per-version data and the longer strings are
+    /// counted, which is most of it for any package with more than a handful of versions.
+    pub fn decoded_size(&self) -> usize {
+        let per_version = size_of::<Version>() + size_of::<Option<TarballUrl>>() + size_of::<Option<Checksum>>()
+            + size_of::<Option<u64>>() + size_of::<Vec<IdxDep>>();
+
+        let tarballs: usize = self.tarballs.iter()
+            .map(|v| match v {
+                Some(TarballUrl::Full(url)) => url.len(),
+                _ => 0
+            })
+            .sum();
+
+        let deps: usize = self.deps.iter().map(|v| v.len() * size_of::<IdxDep>()).sum();
+        let tags: usize = self.dist_tags.keys().map(|v| v.len() + size_of::<(String, usize)>()).sum();
+
+        size_of::<Self>() + self.versions.len() * per_version + tarballs + deps + tags
+    }
+
+    /// Versions are kept sorted, which makes looking one up a binary search.
     fn pos_by_version(&self, version: &Version) -> Option<usize> {
-        self.versions.iter().enumerate()
-            .find(|(_, v)| *v == version)
-            .map(|(v, _)| v)
+        self.versions.binary_search(version).ok()
+    }
+
+    /// Sorts the versions, along with everything stored per version. Idx files are written sorted, this only
+    /// does something for ones that somehow weren't.
+    fn sort_versions(&mut self) {
+        if self.versions.is_sorted() {
+            return
+        }
+
+        let mut order: Vec<usize> = (0..self.versions.len()).collect();
+        order.sort_by(|a, b| self.versions[*a].cmp(&self.versions[*b]));
+
+        let mut new_pos = vec![0; order.len()];
+
+        for (new, old) in order.iter().enumerate() {
+            new_pos[*old] = new;
+        }
+
+        self.versions = permute(std::mem::take(&mut self.versions), &order);
+        self.tarballs = permute(std::mem::take(&mut self.tarballs), &order);
+        self.checksums = permute(std::mem::take(&mut self.checksums), &order);
+        self.sizes = permute(std::mem::take(&mut self.sizes), &order);
+        self.deps = permute(std::mem::take(&mut self.deps), &order);
+
+        self.dist_tags.retain(|_, pos| match new_pos.get(*pos) {
+            Some(new) => {
+                *pos = *new;
+                true
+            },
+            None => false
+        });
     }
 
     fn tarball_by_pos(&self, pos: usize) -> Option<&TarballUrl> {
@@ -149,6 +230,14 @@ impl PackageIndex {
     }
 }
 
+fn permute<T>(values: Vec<T>, order: &[usize]) -> Vec<T> {
+    let mut values: Vec<Option<T>> = values.into_iter().map(Some).collect();
+
+    order.iter()
+        .filter_map(|pos| values.get_mut(*pos).and_then(|v| v.take()))
+        .collect()
+}
+
 fn strip_path(v: &str, package: &str, registry_url: &str) -> TarballUrl {
     v.strip_prefix(registry_url).and_then(|v| {
         v.find(package)
@@ -159,7 +248,7 @@ fn strip_path(v: &str, package: &str, registry_url: &str) -> TarballUrl {
     }).unwrap_or_else(|| TarballUrl::Full(v.to_string()))
 }
 
-pub async fn write_package_idx(buf: &mut Vec<u8>, package: &str, target_path: &Path, pkg_idx: PackageIndex, meta_cache: &RwLock<MetaCache>) -> Result<(), ErrorKind> {
+pub async fn write_package_idx(opts: &CliOpts, buf: &mut Vec<u8>, package: &str, target_path: &Path, pkg_idx: PackageIndex, validators: &Validators, meta_cache: &MetaCache) -> Result<(), ErrorKind> {
     let idx_path = target_path.parent().unwrap().join("index.json.idx");
     let idx_data = bitcode::serialize(&pkg_idx)?;
 
@@ -167,14 +256,24 @@ pub async fn write_package_idx(buf: &mut Vec<u8>, package: &str, target_path: &P
     let compressed = zstd::encode_all(&idx_data[..], 3)?;
 
     buf.clear();
+    buf.write_all(IDX_MAGIC).await?;
+    buf.write_u16(IDX_VERSION).await?;
+
+    for validator in [&validators.etag, &validators.last_modified] {
+        // a validator too long to store is as good as none, the registry just sends the whole document again
+        let validator = validator.as_deref().filter(|v| v.len() <= u16::MAX as usize).unwrap_or_default();
+
+        buf.write_u16(validator.len() as u16).await?;
+        buf.write_all(validator.as_bytes()).await?;
+    }
+
     buf.write_u64(uncompressed_len).await?;
     buf.write_all(&compressed[..]).await?;
 
-    meta_cache.write().await.insert(package, &buf[..]);
+    write_atomic(opts, &idx_path, buf).await?;
 
-    let mut file = tokio::fs::File::create(&idx_path).await?;
-
-    file.write_all(buf).await?;
+    // only cached once written, so that evicting it from the cache can fall back to the file
+    meta_cache.insert(package, &buf[..]);
 
     Ok(())
 }
@@ -186,5 +285,132 @@ pub async fn read_package_idx(opts: &CliOpts, buf: &mut Vec<u8>, package: &str)
     
     buf.reserve_exact(idx_len);
 
-    idx_file.read_to_end(buf).await.map_err(ErrorKind::from)
-}
\ No newline at end of file
+    let start = buf.len();
+    let len = idx_file.read_to_end(buf).await?;
+
+    idx_body(&buf[start..])?;
+
+    Ok(len)
+}
+
+/// Reads just the validators from the idx file of a package, leaving the idx itself alone.
+pub async fn read_idx_validators(opts: &CliOpts, package: &str) -> Result<Validators, ErrorKind> {
+    let mut idx_file = BufReader::new(tokio::fs::File::open(&local_metadata_idx_path(opts, package)).await?);
+
+    let mut header = [0u8; IDX_HEADER_LEN];
+    idx_file.read_exact(&mut header).await?;
+    check_idx_version(&header)?;
+
+    let mut read_validator = async || -> Result<Option<String>, ErrorKind> {
+        let mut validator = vec![0u8; idx_file.read_u16().await? as usize];
+        idx_file.read_exact(&mut validator).await?;
+
+        Ok(String::from_utf8(validator).ok().filter(|v| !v.is_empty()))
+    };
+
+    Ok(Validators {
+        etag: read_validator().await?,
+        last_modified: read_validator().await?,
+    })
+}
+
+/// Decodes idx data as written by `write_package_idx`, using `buf` for the uncompressed data.
+pub fn decode_package_idx(buf: &mut Vec<u8>, data: &[u8]) -> Result<PackageIndex, ErrorKind> {
+    let body = idx_body(data)?;
+
+    let (uncompressed_len, compressed) = body.split_at(size_of::<u64>());
+    let uncompressed_len = u64::from_be_bytes(uncompressed_len.try_into().unwrap());
+
+    buf.resize(uncompressed_len as usize, 0u8);
+
+    zstd::stream::copy_decode(compressed, &mut buf[..])?;
+
+    let mut idx: PackageIndex = bitcode::deserialize(&buf[..])?;
+    idx.sort_versions();
+
+    Ok(idx)
+}
+
+/// Checks the header of idx data and skips past it and the validators.
+fn idx_body(data: &[u8]) -> Result<&[u8], ErrorKind> {
+    check_idx_version(data)?;
+
+    let mut rest = &data[IDX_HEADER_LEN..];
+
+    for _ in 0..2 {
+        let Some((len, after)) = rest.split_first_chunk::<2>() else {
+            return Err(ErrorKind::IdxFormat { version: None })
+        };
+
+        rest = after.get(u16::from_be_bytes(*len) as usize..)
+            .ok_or(ErrorKind::IdxFormat { version: None })?;
+    }
+
+    if rest.len() < size_of::<u64>() {
+        return Err(ErrorKind::IdxFormat { version: None })
+    }
+
+    Ok(rest)
+}
+
+fn check_idx_version(data: &[u8]) -> Result<(), ErrorKind> {
+    if data.len() < IDX_HEADER_LEN || !data.starts_with(IDX_MAGIC) {
+        return Err(ErrorKind::IdxFormat { version: None })
+    }
+
+    let version = u16::from_be_bytes([data[IDX_MAGIC.len()], data[IDX_MAGIC.len() + 1]]);
+
+    if version != IDX_VERSION {
+        return Err(ErrorKind::IdxFormat { version: Some(version) })
+    }
+
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn dep(package: &str) -> Vec<IdxDep> {
+        vec![IdxDep { package: package.into(), range: IdxDepVersion::Tag("latest".into()) }]
+    }
+
+    #[tokio::test]
+    async fn unsorted_idx_is_sorted_on_decode() {
+        let dir = std::env::temp_dir().join(format!("npmmirs-package-index-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("x")).unwrap();
+
+        let opts = CliOpts::parse_from(["npmmirs", "-o", dir.to_str().unwrap()]);
+        let meta_cache = MetaCache::new(&opts);
+
+        let unsorted = PackageIndex {
+            dist_tags: BTreeMap::from([("latest".to_string(), 0), ("next".to_string(), 2), ("old".to_string(), 1)]),
+            versions: ["2.0.0", "1.0.0", "3.0.0-beta.1"].iter().map(|v| v.parse().unwrap()).collect(),
+            tarballs: ["x-2.0.0.tgz", "x-1.0.0.tgz", "x-3.0.0-beta.1.tgz"].iter().map(|v| Some(TarballUrl::Short((*v).into()))).collect(),
+            checksums: vec![None, None, None],
+            sizes: vec![Some(2), Some(1), Some(3)],
+            deps: vec![dep("two"), dep("one"), dep("three")],
+        };
+
+        let mut buf = Vec::new();
+        write_package_idx(&opts, &mut buf, "x", &dir.join("x/index.json"), unsorted, &Validators::default(), &meta_cache).await.unwrap();
+
+        let idx = decode_package_idx(&mut Vec::new(), &buf).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(idx.versions.is_sorted());
+
+        for (version, tarball, dep, size) in [("1.0.0", "x-1.0.0.tgz", "one", 1), ("2.0.0", "x-2.0.0.tgz", "two", 2), ("3.0.0-beta.1", "x-3.0.0-beta.1.tgz", "three", 3)] {
+            let version: Version = version.parse().unwrap();
+
+            assert!(matches!(idx.tarball_by_version(&version), Some(TarballUrl::Short(v)) if v == tarball));
+            assert_eq!(idx.deps_by_version(&version).unwrap()[0].package, dep);
+            assert_eq!(idx.size_by_version(&version), Some(size));
+        }
+
+        assert_eq!(idx.version_by_tag("latest").unwrap().to_string(), "2.0.0");
+        assert_eq!(idx.version_by_tag("old").unwrap().to_string(), "1.0.0");
+        assert_eq!(idx.version_by_tag("next").unwrap().to_string(), "3.0.0-beta.1");
+    }
+}
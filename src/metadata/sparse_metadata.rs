@@ -1,9 +1,14 @@
-use std::{collections::BTreeMap, str::FromStr};
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::{Path, PathBuf}, str::FromStr};
 
 use compact_str::CompactString;
 use nodejs_semver::{Range, Version};
 use serde::{de::Visitor, Deserialize, Serialize};
 
+use crate::error::ErrorKind;
+
+/// Large enough to keep the number of reads down, without holding on to much of a multi-MB document.
+const READ_BUF_LEN: usize = 64 * 1024;
+
 struct VersionRangeDeserializer;
 
 impl<'de> Visitor<'de> for VersionRangeDeserializer {
@@ -13,7 +18,7 @@ impl<'de> Visitor<'de> for VersionRangeDeserializer {
         formatter.write_str("a tag or npm version range")
     }
 
-    fn visit_borrowed_str<E>(self, value: &'de str) -> Result<Self::Value, E>
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
         where
             E: serde::de::Error, {
         
@@ -29,79 +34,160 @@ impl<'de> Visitor<'de> for VersionRangeDeserializer {
                     || value.starts_with("file:")
                     || value.starts_with(".") 
                     || value.starts_with("/") { 
-                    return Ok(DepVersion::Other(value.to_string()))
+                    return Ok(DepVersion::Other(value.into()))
                 }
 
-                if let Some(sub_package) = value.strip_prefix("npm:") {
-                    if let Some((sub_pkg_name, Ok(sub_pkg_v))) = sub_package
-                        .split_once('@')
-                        .map(|(dep, v)| (dep, Range::from_str(v))) {
-                        let sub_dep = SubDep {
-                            package: sub_pkg_name.into(),
-                            range: sub_pkg_v,
-                        };
-
-                        return Ok(DepVersion::SubDep(sub_dep))
-                    }
-
+                if let Some(target) = value.strip_prefix("npm:") {
+                    return Ok(parse_alias(target))
                 }
 
-                Ok(DepVersion::Tag(value.to_string()))
+                Ok(DepVersion::Tag(value.into()))
             }
         }
     }
 }
 
+/// Parses the target of an alias such as `npm:foo@^1.0.0`, `npm:@scope/foo@next` or `npm:foo`, where a missing
+/// version means the latest.
+fn parse_alias(target: &str) -> DepVersion {
+    // the name of a scoped package starts with an @ as well
+    let (package, version) = match target.strip_prefix('@') {
+        Some(scoped) => match scoped.split_once('@') {
+            Some((name, version)) => (&target[..name.len() + 1], version),
+            None => (target, "")
+        },
+        None => target.split_once('@').unwrap_or((target, ""))
+    };
+
+    if version.trim().is_empty() {
+        return DepVersion::SubTag(SubTag { package: package.into(), tag: "latest".into() })
+    }
+
+    match Range::from_str(version) {
+        Ok(range) => DepVersion::SubDep(SubDep { package: package.into(), range }),
+        Err(_) => DepVersion::SubTag(SubTag { package: package.into(), tag: version.into() }),
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub enum DepVersion {
-    Tag(String),
+    Tag(CompactString),
     Range(Range),
     SubDep(SubDep),
-    Other(String),
+    Other(CompactString),
+    SubTag(SubTag),
 }
 
 impl<'de> Deserialize<'de> for DepVersion {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de> {
-        deserializer.deserialize_string(VersionRangeDeserializer)
+        deserializer.deserialize_str(VersionRangeDeserializer)
     }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-pub struct Dep {
-    pub package: String,
-    pub range: DepVersion,
+pub struct SubDep {
+    pub package: CompactString,
+    pub range: Range,
 }
 
+/// An alias for a dist-tag of another package, as in `npm:foo@latest`.
 #[derive(Deserialize, Serialize, Debug)]
-pub struct SubDep {
+pub struct SubTag {
     pub package: CompactString,
-    pub range: Range,
+    pub tag: CompactString,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct SparseMetadata {
-    pub name: String,
+    pub name: CompactString,
     #[serde(rename = "dist-tags")]
-    pub dist_tags: Option<BTreeMap<String, Version>>,
+    pub dist_tags: Option<BTreeMap<CompactString, Version>>,
     pub versions: Option<BTreeMap<Version, Option<VersionInfo>>>,
 }
 
 #[derive(Deserialize, Debug, Default)]
 pub struct VersionInfo {
     pub dist: Dist,
-    pub dependencies: Option<BTreeMap<String, DepVersion>>,
+    pub dependencies: Option<BTreeMap<CompactString, DepVersion>>,
     #[serde(rename = "devDependencies")]
-    pub dev_dependencies: Option<BTreeMap<String, DepVersion>>,
+    pub dev_dependencies: Option<BTreeMap<CompactString, DepVersion>>,
     #[serde(rename = "optionalDependencies")]
-    pub optional_dependencies: Option<BTreeMap<String, DepVersion>>,
+    pub optional_dependencies: Option<BTreeMap<CompactString, DepVersion>>,
     #[serde(rename = "peerDependencies")]
-    pub peer_dependencies: Option<BTreeMap<String, DepVersion>>,
+    pub peer_dependencies: Option<BTreeMap<CompactString, DepVersion>>,
 }
 
 #[derive(Deserialize, Debug, Default)]
 pub struct Dist {
     pub tarball: String,
+    pub integrity: Option<String>,
+    pub shasum: Option<String>,
+    #[serde(rename = "unpackedSize")]
+    pub unpacked_size: Option<u64>,
 }
 
+/// Parses a metadata document straight from disk on the blocking pool. Only the fields needed for resolving are
+/// kept, everything else (readmes, descriptions, maintainers, ...) is skipped over without being read into memory.
+pub async fn read_sparse_metadata(path: &Path) -> Result<SparseMetadata, ErrorKind> {
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || parse_sparse_metadata(path)).await?
+}
+
+fn parse_sparse_metadata(path: PathBuf) -> Result<SparseMetadata, ErrorKind> {
+    let file = File::open(&path)
+        .map_err(|error| ErrorKind::ReadFile { path: path.clone(), error })?;
+
+    Ok(serde_json::from_reader(BufReader::with_capacity(READ_BUF_LEN, file))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dep(value: &str) -> DepVersion {
+        serde_json::from_value(serde_json::Value::String(value.to_string())).unwrap()
+    }
+
+    fn assert_sub_dep(value: &str, package: &str, range: &str) {
+        match dep(value) {
+            DepVersion::SubDep(sub_dep) => {
+                assert_eq!(sub_dep.package, package);
+                assert_eq!(sub_dep.range, Range::from_str(range).unwrap());
+            },
+            other => panic!("expected {package}@{range} for {value}, got {other:?}")
+        }
+    }
+
+    fn assert_sub_tag(value: &str, package: &str, tag: &str) {
+        match dep(value) {
+            DepVersion::SubTag(sub_tag) => {
+                assert_eq!(sub_tag.package, package);
+                assert_eq!(sub_tag.tag, tag);
+            },
+            other => panic!("expected {package}@{tag} for {value}, got {other:?}")
+        }
+    }
+
+    #[test]
+    fn bare_aliases() {
+        assert_sub_dep("npm:foo@^1.0.0", "foo", "^1.0.0");
+        assert_sub_dep("npm:foo@1.2.3", "foo", "1.2.3");
+        assert_sub_tag("npm:foo", "foo", "latest");
+        assert_sub_tag("npm:foo@", "foo", "latest");
+    }
+
+    #[test]
+    fn scoped_aliases() {
+        assert_sub_dep("npm:@scope/foo@~2.1.0", "@scope/foo", "~2.1.0");
+        assert_sub_tag("npm:@scope/foo", "@scope/foo", "latest");
+    }
+
+    #[test]
+    fn tagged_aliases() {
+        assert_sub_tag("npm:foo@next", "foo", "next");
+        assert_sub_tag("npm:@scope/foo@beta", "@scope/foo", "beta");
+    }
+}
@@ -1,302 +1,566 @@
-use std::{fmt::Display, mem, time::Duration};
+use std::{fmt::Display, sync::{atomic::Ordering, Arc}, time::Duration};
 
-use ahash::{HashSet, HashSetExt};
+use ahash::HashSet;
+use dashmap::DashSet;
 use compact_str::{CompactString, ToCompactString};
 use indicatif::{HumanBytes, MultiProgress, ProgressBar};
-use nodejs_semver::{Range, Version};
-use tokio::{fs::read_to_string, sync::RwLock, task::JoinHandle, time::sleep};
+use nodejs_semver::Version;
+use tokio::{fs::read_to_string, sync::mpsc::UnboundedReceiver, task::{JoinHandle, JoinSet}, time::sleep};
 use walkdir::WalkDir;
 
-use crate::{downloader::{Download, Downloader}, error::{ErrorKind, NpmError}, log, meta_cache::MetaCache, metadata::{manifest::Manifest, package_index::{IdxDep, IdxDepVersion, PackageIndex}}, progress::Progress, range_cache::PackageRangeCache, CliOpts};
+use crate::{atomic_file::remove_temp_files, downloader::{Download, Downloader}, error::{ErrorKind, NpmError}, log, meta_cache::MetaCache, offline::{build_report, OfflineReport}, metadata::{manifest::Manifest, package_index::{read_package_idx, IdxDep, IdxDepVersion, PackageIndex}}, progress::Progress, protection::{check_local_metadata, guard_package, guard_tarball}, range_cache::{PackageRangeCache, Requirement}, state::{load_state, remove_state, save_state, Frontier, RunState}, upstream::contained_path, CliOpts};
 
 pub struct MirrorResult {
     new_packages: u64,
     new_packages_bytes: u64,
+    attempts: u64,
+    retries: u64,
 }
 
 impl Display for MirrorResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{} new packages ({})", self.new_packages, HumanBytes(self.new_packages_bytes)))
+        f.write_fmt(format_args!("{} new packages ({})", self.new_packages, HumanBytes(self.new_packages_bytes)))?;
+
+        if self.retries > 0 {
+            f.write_fmt(format_args!(", {} retries over {} download attempts", self.retries, self.attempts))?;
+        }
+
+        Ok(())
     }
 }
 
-pub async fn mirror(opts: &CliOpts, downloader: Downloader, meta_cache: &RwLock<MetaCache>) -> Result<MirrorResult, NpmError> {
-    let range_cache = PackageRangeCache::default();
+pub async fn mirror(opts: &CliOpts, downloader: Downloader, meta_cache: &Arc<MetaCache>) -> Result<MirrorResult, NpmError> {
+    let removed = remove_temp_files(opts).await
+        .map_err(NpmError::Cleanup)?;
+
+    if removed > 0 {
+        log(format!("removed {removed} temporary files left behind by an interrupted run"));
+    }
+
+    if !opts.protected_packages.is_empty() {
+        check_local_metadata(opts).await
+            .map_err(NpmError::Protection)?;
+    }
+
+    let state = match opts.resume {
+        true => load_state(opts).await.map_err(NpmError::State)?,
+        false => None
+    };
+
+    if opts.resume && state.is_none() {
+        log("no saved run state found, starting from scratch");
+    }
+
+    let resumed = state.is_some();
+
+    let (range_cache, frontier) = match state {
+        Some(state) => state.restore(opts.greedy),
+        None => (PackageRangeCache::new(opts.greedy), Frontier::default())
+    };
+
+    let mut resolver = Resolver::new(opts, Some(&downloader), &range_cache, meta_cache, frontier);
+
+    let result = async {
+        downloader.progress().set_total_steps(2);
+        downloader.progress().next_step("Resolving").await;
+
+        resolve(&mut resolver, resumed).await?;
+
+        downloader.progress().advance_step("Downloading").await;
+
+        finish_downloads(&downloader).await
+            .map_err(NpmError::Packages)
+    }.await;
+
+    // TODO: add step to remove non-existing versions from index.json-files
+
+    match &result {
+        Ok(_) => remove_state(opts).await.map_err(NpmError::State)?,
+        Err(e) if e.is_interrupted() => {
+            downloader.shutdown(opts.shutdown_timeout).await;
+
+            log(format!("downloads until interrupted: {}", downloader.progress().files));
+
+            save_state(opts, &RunState::capture(&range_cache, &resolver.frontier())).await
+                .map_err(NpmError::State)?;
+        },
+        Err(_) => ()
+    }
+
+    result
+}
 
+/// Resolves the manifests using only the idx files already in the output folder, without a downloader.
+pub async fn resolve_offline(opts: &CliOpts, meta_cache: &Arc<MetaCache>) -> Result<OfflineReport, NpmError> {
     let mut buf: Vec<u8> = vec![0u8; 1024*8];
 
-    downloader.progress().set_total_steps(3);
-    downloader.progress().next_step("Downloading").await;
+    let range_cache = PackageRangeCache::new(opts.greedy);
+    let mut resolver = Resolver::new(opts, None, &range_cache, meta_cache, Frontier::default());
 
-    download_metadata(opts, &downloader, &range_cache).await
+    resolve(&mut resolver, false).await?;
+
+    build_report(&mut buf, opts, &range_cache, meta_cache).await
+        .map_err(NpmError::Packages)
+}
+
+/// Reads the manifests and resolves their dependencies, while the downloads run in the background.
+async fn resolve(resolver: &mut Resolver<'_>, resumed: bool) -> Result<(), NpmError> {
+    let multibar = MultiProgress::new();
+    let proc_pb = multibar.add(resolver.progress.create_processing_progress_bar().await);
+
+    let mut progress_pairs = vec![(resolver.progress.clone(), proc_pb.clone())];
+
+    if let Some(downloader) = resolver.downloader {
+        let dl_pb = multibar.add(downloader.progress().create_download_progress_bar().await);
+        progress_pairs.push((downloader.progress(), dl_pb));
+    }
+
+    let updater = spawn_updater(progress_pairs).await;
+
+    read_manifests(resolver).await
         .map_err(NpmError::Dependencies)?;
 
-    downloader.progress().next_step("Downloading").await;
+    if resumed {
+        resolver.restore().await
+            .map_err(NpmError::Dependencies)?;
+    }
 
-    download_child_metadata(&mut buf, opts, &downloader, &range_cache, meta_cache).await
+    resolver.run().await
         .map_err(NpmError::ChildDependencies)?;
-    
-    downloader.progress().next_step("Downloading").await;
 
-    let result = download_packages(&mut buf, opts, &downloader, &range_cache, meta_cache).await
-        .map_err(NpmError::Packages)?;
+    updater.abort();
 
-    // TODO: add step to remove non-existing versions from index.json-files
+    resolver.progress.update_for_files(&proc_pb).await;
+    proc_pb.finish_using_style();
 
-    Ok(result)
+    Ok(())
 }
 
-async fn download_packages(buf: &mut Vec<u8>, opts: &CliOpts, downloader: &Downloader, range_cache: &PackageRangeCache,  meta_cache: &RwLock<MetaCache>) -> Result<MirrorResult, ErrorKind> {
-    let multi_bar = MultiProgress::new();
+async fn read_manifests(resolver: &mut Resolver<'_>) -> Result<(), ErrorKind> {
+    for entry in WalkDir::new(&resolver.opts.manifests_path) {
+        if resolver.is_shutting_down() {
+            return Err(ErrorKind::Interrupted)
+        }
 
-    let proc_progress = Progress::with_step("Resolving specifics");
+        let entry = entry?;
 
-    let proc_pb = multi_bar.add(proc_progress.create_processing_progress_bar().await);
-    let dl_pb = multi_bar.add(downloader.progress().create_download_progress_bar().await);
-    
-    let updater = spawn_updater(vec![
-        (proc_progress.clone(), proc_pb.clone()),
-        (downloader.progress(), dl_pb.clone())
-    ]).await;
-
-    let map = range_cache.versions.read().await;
-
-    proc_progress.files.inc_total(map.len() as u64);
-
-    for (package, ranges) in map.iter() {
-        buf.clear();
-        let idx = match meta_cache.read().await.get(buf, package).await {
-            Some(v) => v,
-            None => {
-                // we don't particularly care if we can't read the idx file. probably just means that there is no such
-                // package and the metadata was not downloaded. happens plenty of times during greedy runs, no biggie.
-                if opts.verbose {
-                    log(format!("unable to get idx for {package}, probably was never downloaded"));
-                }
+        if entry.file_type().is_dir() {
+            continue
+        }
 
-                proc_progress.files.inc_failed(1);
-                
-                continue
-            }
-        };
-        
-        if opts.greedy {
-            for version in idx.versions.iter().filter(|v| ranges.satisfies(v)) {
-                let Some(tarball_url) = idx.tarball_by_version(version) else {
-                    continue
-                };
-    
-                downloader.queue(Download::tarball(opts, package, tarball_url)).await?;
-            }
-        } else {
-            for version in ranges.max_satisfying(&idx.versions) {
-                let Some(tarball_url) = idx.tarball_by_version(version) else {
-                    continue
-                };
-    
-                downloader.queue(Download::tarball(opts, package, tarball_url)).await?;
+        let d = read_to_string(entry.path()).await?;
+
+        let manifest: Manifest = serde_json::from_str(&d)?;
+
+        for (package, version) in manifest.dependencies {
+            match requirement(&package, &version.into()) {
+                Some((package, requirement)) => resolver.add_requirement(&package, &requirement).await?,
+                None => if resolver.opts.verbose {
+                    log(format!("skipping {package} in {}, only registry dependencies are mirrored", entry.path().display()));
+                }
             }
         }
-
-        proc_progress.files.inc_success(1);
     }
-    
-    downloader.progress().wait_for_completion(&dl_pb).await;
-    proc_pb.finish_using_style();
+
+    Ok(())
+}
+
+/// Waits for the remaining tarball downloads once resolving is done.
+async fn finish_downloads(downloader: &Downloader) -> Result<MirrorResult, ErrorKind> {
+    let dl_pb = downloader.progress().create_download_progress_bar().await;
+
+    let updater = spawn_updater(vec![(downloader.progress(), dl_pb.clone())]).await;
+
+    downloader.wait_for_completion(&dl_pb).await?;
 
     updater.abort();
 
+    let progress = downloader.progress();
+
     Ok(MirrorResult {
-        new_packages: downloader.progress().files.success(),
-        new_packages_bytes: downloader.progress().bytes.success(),
+        new_packages: progress.tarballs.success(),
+        new_packages_bytes: progress.tarball_bytes.load(Ordering::SeqCst),
+        attempts: progress.attempts(),
+        retries: progress.retries(),
     })
 }
 
-async fn spawn_updater(progress_pairs: Vec<(Progress, ProgressBar)>) -> JoinHandle<()> {
-    tokio::spawn(async move {
-        loop {
-            for (progress, pb) in &progress_pairs {
-                progress.update_for_files(pb).await;
-            }
-    
-            sleep(Duration::from_millis(100)).await
-        }
-    })
+/// Expands dependencies package by package as their metadata arrives, rather than round by round. The tarballs of
+/// selected versions are queued right away, so they download while resolving carries on.
+struct Resolver<'a> {
+    opts: &'a CliOpts,
+    /// Missing when resolving offline, which only ever reads what's on disk.
+    downloader: Option<&'a Downloader>,
+    job: ExpandJob,
+    arrivals: Option<UnboundedReceiver<CompactString>>,
+    /// Packages whose metadata is being downloaded.
+    awaiting: HashSet<CompactString>,
+    /// Packages whose metadata is available, or known to be unavailable.
+    arrived: HashSet<CompactString>,
+    /// Packages whose metadata was still downloading when an earlier run was interrupted. Whatever idx they have
+    /// on disk is older than that, so resuming downloads their metadata again.
+    interrupted: HashSet<CompactString>,
+    /// Packages with metadata that need expanding, because they are new or gained a range.
+    pending: Vec<CompactString>,
+    progress: Progress,
 }
 
-async fn download_child_metadata(buf: &mut Vec<u8>, opts: &CliOpts, downloader: &Downloader, range_cache: &PackageRangeCache, meta_cache: &RwLock<MetaCache>) -> Result<(), ErrorKind> {
-    let proc_progress = Progress::with_step("Resolving children");
+impl<'a> Resolver<'a> {
+    fn new(opts: &'a CliOpts, downloader: Option<&'a Downloader>, range_cache: &PackageRangeCache, meta_cache: &Arc<MetaCache>, frontier: Frontier) -> Self {
+        Self {
+            opts,
+            downloader,
+            job: ExpandJob {
+                opts: Arc::new(opts.clone()),
+                range_cache: range_cache.clone(),
+                meta_cache: meta_cache.clone(),
+                visited: Arc::new(frontier.visited.into_iter().collect()),
+            },
+            arrivals: downloader.and_then(Downloader::metadata_arrivals),
+            awaiting: HashSet::default(),
+            arrived: HashSet::default(),
+            interrupted: frontier.awaiting.into_iter().collect(),
+            pending: frontier.pending,
+            progress: Progress::with_step("Expanding"),
+        }
+    }
 
-    let multibar = MultiProgress::new();
-    let proc_pb = multibar.add(proc_progress.create_processing_progress_bar().await);
-    let dl_pb = multibar.add(downloader.progress().create_download_progress_bar().await);
+    /// What's left to do, for resuming later. Packages still waiting for metadata get it downloaded again.
+    fn frontier(&self) -> Frontier {
+        Frontier {
+            visited: self.job.visited.iter().map(|v| v.clone()).collect(),
+            pending: self.pending.clone(),
+            awaiting: self.awaiting.iter().cloned().collect(),
+        }
+    }
 
-    let updater = spawn_updater(vec![
-        (proc_progress.clone(), proc_pb.clone()),
-        (downloader.progress(), dl_pb.clone())
-    ]).await;
+    /// Adds a version range or dist-tag for a package, requesting its metadata if it's new.
+    async fn add_requirement(&mut self, package: &str, requirement: &Requirement) -> Result<(), ErrorKind> {
+        let res = match requirement {
+            Requirement::Range(range) => self.job.range_cache.insert(package, range),
+            Requirement::Tag(tag) => self.job.range_cache.insert_tag(package, tag),
+        };
 
-    let mut visited = HashSet::<(CompactString, Version)>::new();
+        if res.package_is_new {
+            if !guard_package(self.opts, package)? {
+                self.job.range_cache.remove(package);
+                return Ok(())
+            }
 
-    let mut packages: Vec<CompactString> = range_cache.versions.read().await
-        .keys()
-        .cloned()
-        .collect();
+            return self.request_metadata(package).await
+        }
 
-    proc_progress.files.inc_total(packages.len() as u64);
+        if res.range_is_new && self.arrived.contains(package) {
+            self.push_pending(package);
+        }
 
-    let mut new_packages = Vec::new();
+        Ok(())
+    }
 
-    while let Some(package) = packages.pop() {
-        proc_progress.files.inc_success(1);
+    fn is_shutting_down(&self) -> bool {
+        self.downloader.is_some_and(Downloader::is_shutting_down)
+    }
 
-        buf.clear();
-        let idx = match meta_cache.read().await.get(buf, &package).await {
-            Some(v) => v,
-            None => {
-                if opts.verbose {
-                    log(format!("unable to find idx for {package}, likely not downloaded"));
-                }
+    /// Queues the metadata of a package for download, or when offline, loads it from the local idx.
+    async fn request_metadata(&mut self, package: &str) -> Result<(), ErrorKind> {
+        // neither downloaded nor read from disk, it would end up outside of the mirror
+        if contained_path(package).is_none() {
+            log(format!("skipping package {package}, its name is not a valid path"));
+            self.arrive(package.to_compact_string());
+            return Ok(())
+        }
 
-                range_cache.remove(&package).await;
+        if let Some(downloader) = self.downloader && let Some(dl) = Download::metadata(self.opts, package) {
+            self.awaiting.insert(package.to_compact_string());
+            return downloader.queue(dl).await
+        }
 
-                continue
-            }
-        };
+        let mut buf = Vec::new();
 
-        if opts.greedy {
-            for version in &idx.versions {
-                let pkg_v = (package.clone(), version.clone());
-                if visited.contains(&pkg_v) {
-                    continue 
-                }
-    
-                visited.insert(pkg_v);
-    
-                if !range_cache.satisifies(&package, version).await {
-                    continue
-                }
-    
-                if let Some(deps) = idx.deps_by_version(version) {
-                    populate_child_deps(&idx, opts, deps, downloader, range_cache, &mut new_packages).await?;
+        match read_package_idx(self.opts, &mut buf, package).await {
+            Ok(len) => {
+                self.job.meta_cache.insert(package, &buf[..len]);
+            },
+            Err(e) => {
+                if self.opts.verbose {
+                    log(format!("unable to read idx for {package}: {e}"));
                 }
             }
-        } else {
-            for version in range_cache.max_satisfying(&package, &idx.versions).await {
-                let pkg_v = (package.clone(), version.clone());
-                if visited.contains(&pkg_v) {
-                    continue 
-                }
-    
-                visited.insert(pkg_v);
+        }
+
+        self.arrive(package.to_compact_string());
+
+        Ok(())
+    }
 
-                if let Some(deps) = idx.deps_by_version(version) {
-                    populate_child_deps(&idx, opts, deps, downloader, range_cache, &mut new_packages).await?;
+    fn arrive(&mut self, package: CompactString) {
+        self.awaiting.remove(&package);
+        self.push_pending(&package);
+        self.arrived.insert(package);
+    }
+
+    fn push_pending(&mut self, package: &str) {
+        if !self.pending.iter().any(|v| v == package) {
+            self.progress.files.inc_total(1);
+            self.pending.push(package.to_compact_string());
+        }
+    }
+
+    /// Queues the tarball of a selected version, unless resolving offline.
+    async fn queue_tarball(&self, package: &str, dl: Download) -> Result<(), ErrorKind> {
+        if let Some(downloader) = self.downloader && guard_tarball(self.opts, package, dl.url())? {
+            downloader.queue(dl).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the metadata of every package known from an interrupted run from disk, queueing the ones that never
+    /// finished downloading, or were still downloading when interrupted, along with the tarballs of the versions
+    /// selected so far.
+    async fn restore(&mut self) -> Result<(), ErrorKind> {
+        let mut buf = Vec::new();
+
+        let packages: Vec<CompactString> = self.job.range_cache.versions.iter().map(|entry| entry.key().clone()).collect();
+
+        for package in packages {
+            buf.clear();
+
+            let idx_len = match contained_path(&package) {
+                Some(_) if !self.interrupted.contains(&package) => read_package_idx(self.opts, &mut buf, &package).await.ok(),
+                _ => None
+            };
+
+            match idx_len {
+                Some(len) => {
+                    self.job.meta_cache.insert(&package, &buf[..len]);
+                    self.arrived.insert(package);
+                },
+                None => {
+                    self.pending.retain(|v| *v != package);
+                    self.request_metadata(&package).await?;
                 }
             }
         }
 
-        if packages.is_empty() {
-            proc_progress.set_step("Waiting for next round").await;
+        self.progress.files.inc_total(self.pending.len() as u64);
 
-            mem::swap(&mut packages, &mut new_packages);
-            downloader.progress().wait_for_idle(&dl_pb).await;
+        let visited: Vec<(CompactString, Version)> = self.job.visited.iter().map(|v| v.clone()).collect();
 
-            if packages.is_empty() {
-                proc_progress.set_step("Resolving done").await;
-            } else {
-                proc_progress.set_step("Resolving children").await;
-            }
+        for (package, version) in visited {
+            buf.clear();
+
+            let dl = self.job.meta_cache.get(&mut buf, &package)
+                .and_then(|idx| tarball_download(self.opts, &package, &idx, &version));
 
-            proc_progress.files.reset();
-            proc_progress.files.inc_total(packages.len() as u64);
+            if let Some(dl) = dl {
+                self.queue_tarball(&package, dl).await?;
+            }
         }
+
+        Ok(())
     }
 
-    updater.abort();
+    /// Expands pending packages on the blocking pool, and waits for more metadata whenever there are none, until
+    /// no metadata is left to wait for.
+    async fn run(&mut self) -> Result<(), ErrorKind> {
+        let parallelism = std::thread::available_parallelism().map(|v| v.get()).unwrap_or(4);
 
-    dl_pb.finish_using_style();
-    proc_pb.finish_using_style();
-    
-    Ok(())
-}
+        let mut expansions = JoinSet::new();
+        let mut arrivals = self.arrivals.take();
 
-async fn populate_child_deps(idx: &PackageIndex, opts: &CliOpts, deps: &Vec<IdxDep>, downloader: &Downloader, range_cache: &PackageRangeCache, packages: &mut Vec<CompactString>) -> Result<(), ErrorKind> {
-    for dep in deps {
-        match &dep.range {
-            IdxDepVersion::Tag(tag) => {
-                if let Some(version) = idx.version_by_tag(tag.as_str()) {
-                    let range = Range::parse(version.to_compact_string())?;
-                    process_version(opts, downloader, range_cache, &dep.package, &range, packages).await?;
+        loop {
+            if self.is_shutting_down() {
+                self.discard(expansions).await;
+                return Err(ErrorKind::Interrupted)
+            }
+
+            while expansions.len() < parallelism && let Some(package) = self.pending.pop() {
+                let job = self.job.clone();
+                expansions.spawn_blocking(move || job.expand(package));
+            }
+
+            if expansions.is_empty() && (self.awaiting.is_empty() || arrivals.is_none()) {
+                return Ok(())
+            }
+
+            tokio::select! {
+                Some(expansion) = expansions.join_next(), if !expansions.is_empty() => {
+                    self.apply(expansion??).await?;
+                    self.progress.files.inc_success(1);
+                },
+                Some(package) = recv(&mut arrivals), if !self.awaiting.is_empty() => self.arrive(package),
+                // wake up every now and then to notice shutdowns
+                _ = sleep(Duration::from_millis(100)) => ()
+            }
+        }
+    }
+
+    /// Hands expansions still in flight back to pending, so that resuming selects their versions again.
+    async fn discard(&mut self, mut expansions: JoinSet<Result<Expansion, ErrorKind>>) {
+        while let Some(expansion) = expansions.join_next().await {
+            if let Ok(Ok(expansion)) = expansion {
+                for version in expansion.selected {
+                    self.job.visited.remove(&(expansion.package.clone(), version));
                 }
-            },
-            IdxDepVersion::Range(range) => {
-                process_version(opts, downloader, range_cache, &dep.package, range, packages).await?;
-            },
-            IdxDepVersion::SubDep(sub_dep) => {
-                process_version(opts, downloader, range_cache, &sub_dep.package, &sub_dep.range, packages).await?;
-            },
-            _ => (),
+
+                self.pending.push(expansion.package);
+            }
         }
     }
 
-    Ok(())
-}
+    /// Queues what expanding a package turned up, and adds the ranges of its dependencies.
+    async fn apply(&mut self, expansion: Expansion) -> Result<(), ErrorKind> {
+        let Expansion { package, found, tarballs, deps, .. } = expansion;
+
+        if !found {
+            if self.opts.verbose {
+                log(format!("unable to find idx for {package}, likely not downloaded"));
+            }
+
+            // offline, the ranges of missing packages are kept around for the report
+            if !self.opts.offline {
+                self.job.range_cache.remove(&package);
+            }
 
-async fn process_version(opts: &CliOpts, downloader: &Downloader, range_cache: &PackageRangeCache, dep: &str, version_range: &Range, packages: &mut Vec<CompactString>) -> Result<(), ErrorKind> {
-    let res = range_cache.insert(dep, version_range).await;
-            
-    if res.package_is_new {
-        downloader.queue(Download::metadata(opts, dep)).await?;
+            return Ok(())
+        }
+
+        for dl in tarballs {
+            self.queue_tarball(&package, dl).await?;
+        }
+
+        for (dep, requirement) in deps {
+            self.add_requirement(&dep, &requirement).await?;
+        }
+
+        Ok(())
     }
-    
-    if (res.package_is_new || res.range_is_new) && !packages.iter().any(|v| v == dep) {
-        packages.push(dep.to_compact_string());
+}
+
+async fn recv(arrivals: &mut Option<UnboundedReceiver<CompactString>>) -> Option<CompactString> {
+    match arrivals {
+        Some(arrivals) => arrivals.recv().await,
+        None => None
     }
+}
 
-    Ok(())
+/// Everything expanding a package needs, detached from the resolver so it can run on the blocking pool. Decoding
+/// idx files is what keeps the resolver busy on large mirrors.
+#[derive(Clone)]
+struct ExpandJob {
+    opts: Arc<CliOpts>,
+    range_cache: PackageRangeCache,
+    meta_cache: Arc<MetaCache>,
+    visited: Arc<DashSet<(CompactString, Version)>>,
 }
 
-async fn download_metadata(opts: &CliOpts, downloader: &Downloader, range_cache: &PackageRangeCache) -> Result<(), ErrorKind> {
-    let proc_progress = Progress::with_step("Reading manifests");
+struct Expansion {
+    package: CompactString,
+    found: bool,
+    selected: Vec<Version>,
+    tarballs: Vec<Download>,
+    deps: Vec<(CompactString, Requirement)>,
+}
 
-    let multibar = MultiProgress::new();
-    let proc_pb = multibar.add(proc_progress.create_processing_progress_bar().await);
-    let dl_pb = multibar.add(downloader.progress().create_download_progress_bar().await);
+impl ExpandJob {
+    /// Selects the versions of a package its ranges call for, and collects the tarballs and dependencies of the
+    /// ones not selected before.
+    fn expand(&self, package: CompactString) -> Result<Expansion, ErrorKind> {
+        let mut expansion = Expansion { package, found: false, selected: Vec::new(), tarballs: Vec::new(), deps: Vec::new() };
+        let package = &expansion.package;
 
-    let updater = spawn_updater(vec![
-        (proc_progress.clone(), proc_pb.clone()),
-        (downloader.progress(), dl_pb.clone())
-    ]).await;
+        let mut buf = Vec::new();
+        let Some(idx) = self.meta_cache.get(&mut buf, package) else {
+            return Ok(expansion)
+        };
 
-    for entry in WalkDir::new(&opts.manifests_path) {
-        let entry = entry?;
+        expansion.found = true;
 
-        if entry.file_type().is_dir() {
-            continue
+        // dist-tags can only be resolved now, as they are tags of this package rather than the dependent's
+        for tag in self.range_cache.resolve_tags(package, &idx) {
+            if self.opts.verbose {
+                log(format!("no dist-tag {tag} for {package}"));
+            }
         }
 
-        proc_progress.files.inc_total(1);
+        let versions: Vec<&Version> = match self.opts.greedy {
+            true => idx.versions.iter().filter(|v| self.range_cache.satisifies(package, v)).collect(),
+            false => self.range_cache.max_satisfying(package, &idx.versions)
+        };
 
-        let d = read_to_string(entry.path()).await?;
+        for version in versions {
+            // parallel expansions of the same package only ever get to select a version once
+            if !self.visited.insert((package.clone(), version.clone())) {
+                continue
+            }
 
-        let manifest: Manifest = serde_json::from_str(&d)?;
+            expansion.selected.push(version.clone());
 
-        for (package, version_range) in manifest.dependencies {
-            let res = range_cache.insert(&package, &version_range).await;
+            if !self.opts.offline && let Some(dl) = tarball_download(&self.opts, package, &idx, version) {
+                expansion.tarballs.push(dl);
+            }
 
-            if res.package_is_new {
-                downloader.queue(Download::metadata(opts, &package)).await?;
+            if let Some(deps) = idx.deps_by_version(version) {
+                collect_child_deps(deps, &mut expansion.deps);
             }
         }
-        
-        proc_progress.files.inc_success(1);
+
+        Ok(expansion)
     }
+}
 
-    proc_pb.finish_using_style();
-    downloader.progress().wait_for_completion(&dl_pb).await;
+fn collect_child_deps(deps: &[IdxDep], requirements: &mut Vec<(CompactString, Requirement)>) {
+    requirements.extend(deps.iter().filter_map(|dep| requirement(&dep.package, &dep.range)));
+}
 
-    updater.abort();
+/// The package and version a dependency needs mirrored, which for aliases is the package they point to.
+/// Dependencies from outside the registry, such as git urls, have none.
+fn requirement(package: &CompactString, version: &IdxDepVersion) -> Option<(CompactString, Requirement)> {
+    match version {
+        IdxDepVersion::Tag(tag) => Some((package.clone(), Requirement::Tag(tag.clone()))),
+        IdxDepVersion::Range(range) => Some((package.clone(), Requirement::Range(range.clone()))),
+        IdxDepVersion::SubDep(sub_dep) => Some((sub_dep.package.clone(), Requirement::Range(sub_dep.range.clone()))),
+        IdxDepVersion::SubTag(sub_tag) => Some((sub_tag.package.clone(), Requirement::Tag(sub_tag.tag.clone()))),
+        IdxDepVersion::Other(_) => None,
+    }
+}
 
-    Ok(())
+fn tarball_download(opts: &CliOpts, package: &str, idx: &PackageIndex, version: &Version) -> Option<Download> {
+    let tarball_url = idx.tarball_by_version(version)?;
+
+    let checksum = idx.checksum_by_version(version);
+
+    if checksum.is_none() && opts.verbose {
+        log(format!("no checksum for {package} tarball, it will not be verified"));
+    }
+
+    Download::tarball(opts, package, tarball_url, checksum, idx.size_by_version(version))
+}
+
+/// Keeps the progress bars up to date until aborted or dropped.
+struct Updater(JoinHandle<()>);
+
+impl Updater {
+    fn abort(&self) {
+        self.0.abort();
+    }
+}
+
+impl Drop for Updater {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn spawn_updater(progress_pairs: Vec<(Progress, ProgressBar)>) -> Updater {
+    Updater(tokio::spawn(async move {
+        loop {
+            for (progress, pb) in &progress_pairs {
+                progress.update_for_files(pb).await;
+            }
+    
+            sleep(Duration::from_millis(100)).await
+        }
+    }))
 }
@@ -0,0 +1,110 @@
+use std::fmt::Display;
+
+use compact_str::CompactString;
+use nodejs_semver::Version;
+
+use crate::{downloader::Download, error::ErrorKind, log, meta_cache::MetaCache, range_cache::PackageRangeCache, CliOpts};
+
+pub struct OfflineReport {
+    pub resolved: Vec<(CompactString, Version)>,
+    pub unsatisfied: Vec<Unsatisfied>,
+}
+
+pub struct Unsatisfied {
+    pub package: CompactString,
+    pub range: String,
+    pub missing: Missing,
+}
+
+pub enum Missing {
+    Metadata,
+    Version,
+    Tarball(Version),
+}
+
+impl Display for Unsatisfied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.missing {
+            Missing::Metadata => f.write_fmt(format_args!("{}@{}: no metadata in the mirror", self.package, self.range)),
+            Missing::Version => f.write_fmt(format_args!("{}@{}: no matching version in the mirror", self.package, self.range)),
+            Missing::Tarball(version) => f.write_fmt(format_args!("{}@{}: resolves to {version}, but its tarball is not in the mirror", self.package, self.range)),
+        }
+    }
+}
+
+impl Display for OfflineReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{} versions resolved, {} unsatisfied", self.resolved.len(), self.unsatisfied.len()))
+    }
+}
+
+impl OfflineReport {
+    pub fn is_satisfied(&self) -> bool {
+        self.unsatisfied.is_empty()
+    }
+
+    pub fn log(&self) {
+        for (package, version) in &self.resolved {
+            log(format!("resolved {package}@{version}"));
+        }
+
+        for unsatisfied in &self.unsatisfied {
+            log(format!("unsatisfied {unsatisfied}"));
+        }
+    }
+}
+
+/// Checks every range against the local metadata and tarballs, just like downloading the packages would.
+pub async fn build_report(buf: &mut Vec<u8>, opts: &CliOpts, range_cache: &PackageRangeCache, meta_cache: &MetaCache) -> Result<OfflineReport, ErrorKind> {
+    let mut report = OfflineReport { resolved: Vec::new(), unsatisfied: Vec::new() };
+
+    for entry in range_cache.versions.iter() {
+        let (package, ranges) = entry.pair();
+
+        buf.clear();
+        let Some(idx) = meta_cache.get(buf, package) else {
+            let range = ranges.inner.iter().map(|r| r.to_string())
+                .chain(ranges.tags.iter().map(|t| t.to_string()))
+                .collect::<Vec<_>>()
+                .join(" || ");
+            report.unsatisfied.push(Unsatisfied { package: package.clone(), range, missing: Missing::Metadata });
+            continue
+        };
+
+        // known tags were turned into ranges while resolving
+        for tag in &ranges.tags {
+            if idx.version_by_tag(tag).is_none() {
+                report.unsatisfied.push(Unsatisfied { package: package.clone(), range: tag.to_string(), missing: Missing::Version });
+            }
+        }
+
+        for range in &ranges.inner {
+            let versions: Vec<&Version> = match opts.greedy {
+                true => idx.versions.iter().filter(|v| range.satisfies(v)).collect(),
+                false => range.max_satisfying(&idx.versions).into_iter().collect(),
+            };
+
+            if versions.is_empty() {
+                report.unsatisfied.push(Unsatisfied { package: package.clone(), range: range.to_string(), missing: Missing::Version });
+            }
+
+            for version in versions {
+                let in_mirror = idx.tarball_by_version(version)
+                    .and_then(|url| Download::tarball(opts, package, url, None, None))
+                    .is_some_and(|dl| dl.target_path().exists());
+
+                if !in_mirror {
+                    report.unsatisfied.push(Unsatisfied { package: package.clone(), range: range.to_string(), missing: Missing::Tarball(version.clone()) });
+                } else {
+                    report.resolved.push((package.clone(), version.clone()));
+                }
+            }
+        }
+    }
+
+    report.resolved.sort();
+    report.resolved.dedup();
+    report.unsatisfied.sort_by(|a, b| a.package.cmp(&b.package));
+
+    Ok(report)
+}
@@ -0,0 +1,62 @@
+use std::{fmt::Display, str::FromStr};
+
+use compact_str::{CompactString, ToCompactString};
+
+/// A package name pattern. Either an exact package name or a glob where `*` matches any number of characters
+/// and `?` matches exactly one, e.g. `@acme/*`.
+#[derive(Clone, Debug)]
+pub struct PackagePattern(CompactString);
+
+impl PackagePattern {
+    pub fn matches(&self, package: &str) -> bool {
+        glob_match(self.0.as_bytes(), package.as_bytes())
+    }
+}
+
+impl FromStr for PackagePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if s.is_empty() {
+            return Err("package pattern can not be empty".to_string())
+        }
+
+        Ok(Self(s.to_compact_string()))
+    }
+}
+
+impl Display for PackagePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn glob_match(pattern: &[u8], value: &[u8]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            },
+            Some(&c) if c == b'?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            },
+            _ => match backtrack {
+                Some((bp, bv)) => {
+                    backtrack = Some((bp, bv + 1));
+                    p = bp + 1;
+                    v = bv + 1;
+                },
+                None => return false
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
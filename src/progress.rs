@@ -11,8 +11,13 @@ pub struct Progress {
     step_name: Arc<Mutex<CompactString>>,
     pub files: ProgressPart,
     pub bytes: ProgressPart,
+    /// Downloaded tarballs and their bytes, a subset of `files` and `bytes`.
+    pub tarballs: ProgressPart,
+    pub tarball_bytes: Arc<AtomicU64>,
     pub total_bytes: Arc<AtomicU64>,
-    total_steps: Arc<AtomicU8>
+    total_steps: Arc<AtomicU8>,
+    attempts: Arc<AtomicU64>,
+    retries: Arc<AtomicU64>,
 }
 
 impl Progress {
@@ -22,8 +27,12 @@ impl Progress {
             step: Arc::new(AtomicU8::new(0)),
             files: ProgressPart::new(),
             bytes: ProgressPart::new(),
+            tarballs: ProgressPart::new(),
+            tarball_bytes: Arc::new(AtomicU64::new(0)),
             total_bytes: Arc::new(AtomicU64::new(0)),
-            total_steps: Arc::new(AtomicU8::new(4))
+            total_steps: Arc::new(AtomicU8::new(4)),
+            attempts: Arc::new(AtomicU64::new(0)),
+            retries: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -33,8 +42,12 @@ impl Progress {
             step: Arc::new(AtomicU8::new(0)),
             files: ProgressPart::new(),
             bytes: ProgressPart::new(),
+            tarballs: ProgressPart::new(),
+            tarball_bytes: Arc::new(AtomicU64::new(0)),
             total_bytes: Arc::new(AtomicU64::new(0)),
-            total_steps: Arc::new(AtomicU8::new(4))
+            total_steps: Arc::new(AtomicU8::new(4)),
+            attempts: Arc::new(AtomicU64::new(0)),
+            retries: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -100,19 +113,41 @@ impl Progress {
     pub async fn update_for_files(&self, progress_bar: &ProgressBar) {
         progress_bar.set_length(self.files.total());
         progress_bar.set_position(self.files.total() - self.files.remaining());
-        progress_bar.set_message(HumanBytes(self.bytes.success()).to_compact_string());
+        match self.retries() {
+            0 => progress_bar.set_message(HumanBytes(self.bytes.success()).to_compact_string()),
+            retries => progress_bar.set_message(format!("{}, {retries} retries", HumanBytes(self.bytes.success())))
+        }
 
         if self.step.load(Ordering::SeqCst) == 0 {
             progress_bar.set_prefix(self.create_prefix_stepless().await);
         }
     }
 
+    pub fn inc_attempts(&self) {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn inc_retries(&self) {
+        self.retries.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn attempts(&self) -> u64 {
+        self.attempts.load(Ordering::SeqCst)
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::SeqCst)
+    }
+
     pub fn set_total_steps(&self, num_steps: u8) {
         self.total_steps.store(num_steps, Ordering::SeqCst);
     }
 
-    pub async fn set_step(&self, step_name: &str) {
+    /// Moves on to the next step while downloads carry on, keeping all counts.
+    pub async fn advance_step(&self, step_name: &str) {
         *self.step_name.lock().await = step_name.to_compact_string();
+
+        self.step.fetch_add(1, Ordering::SeqCst);
     }
 
     pub async fn next_step(&self, step_name: &str) {
@@ -120,19 +155,12 @@ impl Progress {
 
         self.bytes.reset();
         self.files.reset();
+        self.attempts.store(0, Ordering::SeqCst);
+        self.retries.store(0, Ordering::SeqCst);
 
         self.step.fetch_add(1, Ordering::SeqCst);
     }
     
-    pub async fn wait_for_idle(&self, progress_bar: &ProgressBar)  {
-        while self.files.remaining() > 0 {
-            self.update_for_files(progress_bar).await;
-            sleep(Duration::from_millis(100)).await
-        }
-
-        self.update_for_files(progress_bar).await;
-    }
-
     pub async fn wait_for_completion(&self, progress_bar: &ProgressBar)  {
         while self.files.remaining() > 0 {
             self.update_for_files(progress_bar).await;
@@ -185,6 +213,14 @@ impl ProgressPart {
         self.success.fetch_add(count, Ordering::SeqCst);
     }
 
+    pub fn dec_total(&self, count: u64) {
+        self.total.fetch_sub(count, Ordering::SeqCst);
+    }
+
+    pub fn dec_success(&self, count: u64) {
+        self.success.fetch_sub(count, Ordering::SeqCst);
+    }
+
     pub fn inc_skipped(&self, count: u64) {
         self.skipped.fetch_add(count, Ordering::SeqCst);
     }
@@ -0,0 +1,221 @@
+use clap::ValueEnum;
+use compact_str::{CompactString, ToCompactString};
+use reqwest::Url;
+
+use crate::{error::ErrorKind, log, metadata::{local_abbreviated_metadata_path, local_metadata_path, sparse_metadata::{read_sparse_metadata, SparseMetadata}}, CliOpts};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ProtectedAction {
+    /// Abort the run
+    Error,
+    /// Log the package and leave it out of the mirror
+    Skip,
+}
+
+pub fn is_protected(opts: &CliOpts, package: &str) -> bool {
+    opts.protected_packages.iter().any(|p| p.matches(package))
+}
+
+/// The registry that metadata and short tarball paths for the package are resolved against.
+pub fn registry_url_for<'a>(opts: &'a CliOpts, package: &str) -> &'a str {
+    match &opts.protected_registry_url {
+        Some(protected_registry_url) if is_protected(opts, package) => protected_registry_url,
+        _ => &opts.registry_url
+    }
+}
+
+/// Checks whether the package may be resolved at all. Protected packages are only resolvable when a protected
+/// registry is configured, otherwise they would be looked up in the public upstream.
+/// 
+/// Returns `Ok(false)` if the package should be skipped.
+pub fn guard_package(opts: &CliOpts, package: &str) -> Result<bool, ErrorKind> {
+    if opts.protected_registry_url.is_some() || !is_protected(opts, package) {
+        return Ok(true)
+    }
+
+    violation(opts, ErrorKind::ProtectedPackage {
+        package: package.to_string(),
+        origin: opts.registry_url.to_string()
+    })
+}
+
+/// Checks that a tarball for a protected package is fetched from the protected registry.
+/// 
+/// Returns `Ok(false)` if the tarball should be skipped.
+pub fn guard_tarball(opts: &CliOpts, package: &str, url: &str) -> Result<bool, ErrorKind> {
+    if !is_protected(opts, package) || is_from_protected_registry(opts, url) {
+        return Ok(true)
+    }
+
+    violation(opts, ErrorKind::ProtectedPackage {
+        package: package.to_string(),
+        origin: url.to_string()
+    })
+}
+
+/// Flags protected packages whose metadata already on disk was not fetched from the protected registry.
+pub async fn check_local_metadata(opts: &CliOpts) -> Result<(), ErrorKind> {
+    let mut flagged = 0;
+
+    for package in local_packages(opts).await? {
+        if !is_protected(opts, &package) {
+            continue
+        }
+
+        let metadata = match read_local_metadata(opts, &package).await? {
+            Some(v) => v,
+            None => continue
+        };
+
+        let mut tarballs = metadata.versions.iter()
+            .flatten()
+            .filter_map(|(_, info)| info.as_ref())
+            .map(|info| info.dist.tarball.as_str());
+
+        let origin = if opts.protected_registry_url.is_none() {
+            tarballs.next().unwrap_or(&opts.registry_url)
+        } else {
+            match tarballs.find(|url| !is_from_protected_registry(opts, url)) {
+                Some(url) => url,
+                None => continue
+            }
+        };
+
+        flagged += 1;
+
+        log(format!("flagged local metadata for protected package {package}, resolved from {origin}"));
+    }
+
+    if flagged > 0 && opts.protected_action == ProtectedAction::Error {
+        return Err(ErrorKind::ProtectedMetadata { count: flagged })
+    }
+
+    Ok(())
+}
+
+/// Reads whichever metadata document of the package is on disk.
+async fn read_local_metadata(opts: &CliOpts, package: &str) -> Result<Option<SparseMetadata>, ErrorKind> {
+    for path in [local_metadata_path(opts, package), local_abbreviated_metadata_path(opts, package)] {
+        match read_sparse_metadata(&path).await {
+            Ok(v) => return Ok(Some(v)),
+            Err(ErrorKind::ReadFile { error, .. }) if error.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(None)
+}
+
+fn is_from_protected_registry(opts: &CliOpts, url: &str) -> bool {
+    opts.protected_registry_url.as_ref()
+        .is_some_and(|registry_url| url_is_under(url, registry_url))
+}
+
+/// Whether the url points to the same origin as the base url, and to its path or anything below it. A plain string
+/// prefix isn't enough, as `https://npm.acme.internal` is a prefix of `https://npm.acme.internal.evil.com` too.
+pub fn url_is_under(url: &str, base: &str) -> bool {
+    let (Ok(url), Ok(base)) = (Url::parse(url), Url::parse(base)) else {
+        return false
+    };
+
+    if url.scheme() != base.scheme()
+        || url.host_str() != base.host_str()
+        || url.port_or_known_default() != base.port_or_known_default() {
+        return false
+    }
+
+    let base_path = base.path().trim_end_matches('/');
+
+    url.path() == base_path || url.path().strip_prefix(base_path).is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn violation(opts: &CliOpts, error: ErrorKind) -> Result<bool, ErrorKind> {
+    match opts.protected_action {
+        ProtectedAction::Error => Err(error),
+        ProtectedAction::Skip => {
+            log(format!("skipping: {error}"));
+            Ok(false)
+        }
+    }
+}
+
+async fn local_packages(opts: &CliOpts) -> Result<Vec<CompactString>, ErrorKind> {
+    let output_base = opts.output.strip_suffix('/').unwrap_or(&opts.output);
+
+    let mut packages = Vec::new();
+
+    for (name, is_dir) in list_dir(output_base).await? {
+        if !is_dir {
+            continue
+        }
+
+        if !name.starts_with('@') {
+            packages.push(name);
+            continue
+        }
+
+        for (scoped_name, is_dir) in list_dir(&format!("{output_base}/{name}")).await? {
+            if is_dir {
+                packages.push(format!("{name}/{scoped_name}").to_compact_string());
+            }
+        }
+    }
+
+    Ok(packages)
+}
+
+/// Lists the non-hidden entries in a directory, along with whether they are directories themselves.
+async fn list_dir(path: &str) -> Result<Vec<(CompactString, bool)>, ErrorKind> {
+    let mut read_dir = match tokio::fs::read_dir(path).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into())
+    };
+
+    let mut entries = Vec::new();
+
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name();
+
+        let Some(name) = name.to_str() else {
+            continue
+        };
+
+        if name.starts_with('.') {
+            continue
+        }
+
+        entries.push((name.to_compact_string(), entry.file_type().await?.is_dir()));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_under_registry() {
+        assert!(url_is_under("https://npm.acme.internal/@acme/x/-/x-1.0.0.tgz", "https://npm.acme.internal"));
+        assert!(url_is_under("https://npm.acme.internal/@acme/x/-/x-1.0.0.tgz", "https://npm.acme.internal/"));
+        assert!(url_is_under("https://npm.acme.internal:443/x/-/x-1.0.0.tgz", "https://npm.acme.internal"));
+        assert!(url_is_under("https://NPM.acme.internal/x/-/x-1.0.0.tgz", "https://npm.acme.internal"));
+        assert!(url_is_under("https://acme.internal/npm/x/-/x-1.0.0.tgz", "https://acme.internal/npm/"));
+    }
+
+    #[test]
+    fn lookalike_hosts_are_not_under_registry() {
+        assert!(!url_is_under("https://npm.acme.internal.evil.com/x/-/x-1.0.0.tgz", "https://npm.acme.internal"));
+        assert!(!url_is_under("https://npm.acme.internalx/x/-/x-1.0.0.tgz", "https://npm.acme.internal"));
+        assert!(!url_is_under("https://npm.acme.internal@evil.com/x/-/x-1.0.0.tgz", "https://npm.acme.internal"));
+        assert!(!url_is_under("http://npm.acme.internal/x/-/x-1.0.0.tgz", "https://npm.acme.internal"));
+        assert!(!url_is_under("https://npm.acme.internal:8443/x/-/x-1.0.0.tgz", "https://npm.acme.internal"));
+    }
+
+    #[test]
+    fn path_prefix_needs_a_boundary() {
+        assert!(!url_is_under("https://acme.internal/npm-public/x/-/x-1.0.0.tgz", "https://acme.internal/npm"));
+        assert!(!url_is_under("https://acme.internal/x/-/x-1.0.0.tgz", "https://acme.internal/npm/"));
+    }
+}
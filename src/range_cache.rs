@@ -1,114 +1,529 @@
-use std::sync::Arc;
+use std::{cmp::Ordering, sync::Arc};
 
-use ahash::{HashMap, HashSet};
 use compact_str::{CompactString, ToCompactString};
-use nodejs_semver::{Range, Version};
-use tokio::sync::RwLock;
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use nodejs_semver::{Identifier, Range, Version};
 
+use crate::metadata::package_index::PackageIndex;
 
-#[derive(Default)]
+
+/// The version ranges every package is needed in. Safe to use from parallel resolver tasks.
+#[derive(Default, Clone)]
 pub struct PackageRangeCache {
-    pub versions: Arc<RwLock<HashMap<CompactString, Ranges>>>,
-    pub removed: Arc<RwLock<HashSet<CompactString>>>,
+    pub versions: Arc<DashMap<CompactString, Ranges>>,
+    pub removed: Arc<DashSet<CompactString>>,
+    greedy: bool,
 }
 
+/// The ranges of a single package. Mirroring greedily needs every version in the union of the ranges, so a range
+/// the union already covers adds nothing. Otherwise every range selects its own highest version, and a range only
+/// adds nothing if it's equivalent to one already here, as `^1.0.0` and `~1.2.0` select different versions even
+/// though one covers the other.
+#[derive(Default)]
 pub struct Ranges {
     pub inner: Vec<Range>,
+    /// Dist-tags the package was asked for, which only its own metadata can turn into versions.
+    pub tags: Vec<CompactString>,
+    /// The bounds of every range in `inner`, normalized. `None` for ranges whose bounds couldn't be worked out,
+    /// which are matched as they are.
+    normalized: Vec<Option<Vec<Span>>>,
+    /// The union of all ranges, with overlapping and adjacent ranges merged.
+    union: Vec<Span>,
 }
 
 impl Ranges {
-    pub fn satisfies(&self, version: &Version) -> bool {
-        for range in &self.inner {
-            if range.satisfies(version) {
-                return true
-            }
+    pub fn new(inner: Vec<Range>, greedy: bool) -> Self {
+        let mut ranges = Self::default();
+
+        for range in inner {
+            ranges.insert(range, greedy);
         }
 
-        false
+        ranges
+    }
+
+    pub fn satisfies(&self, version: &Version) -> bool {
+        self.union.iter().any(|span| span.satisfies(version)) ||
+            self.inner.iter().zip(&self.normalized)
+                .any(|(range, normalized)| normalized.is_none() && range.satisfies(version))
     }
 
+    /// The highest version every range selects, in `versions` sorted from lowest to highest.
     pub fn max_satisfying<'a>(&self, versions: &'a [Version]) -> Vec<&'a Version> {
         let mut max_satisifying = Vec::new();
 
         for range in &self.inner {
-            if let Some(s) = range.max_satisfying(versions) {
+            if let Some(s) = versions.iter().rev().find(|v| range.satisfies(v)) && !max_satisifying.contains(&s) {
                 max_satisifying.push(s);
             }
         }
-        
+
         max_satisifying
     }
-}
 
-impl PackageRangeCache {
-    pub async fn max_satisfying<'a>(&self, package: &str, versions: &'a [Version]) -> Vec<&'a Version> {
-        {
-            if self.removed.read().await.contains(package) {
-                return Vec::new()
+    /// Adds the range unless it adds nothing, returning whether it was added.
+    fn insert(&mut self, range: Range, greedy: bool) -> bool {
+        let Some(spans) = Span::parse_range(&range) else {
+            if self.inner.contains(&range) {
+                return false
             }
-        }
 
-        let map = self.versions.read().await;
+            self.inner.push(range);
+            self.normalized.push(None);
+            return true
+        };
 
-        if let Some(ranges) = map.get(package) {
-            return ranges.max_satisfying(versions)
+        let spans = merge(spans);
+
+        let covered = match greedy {
+            true => spans.iter().all(|span| self.union.iter().any(|u| u.contains(span))),
+            false => self.normalized.iter().any(|n| n.as_ref() == Some(&spans))
+        };
+
+        if covered {
+            return false
         }
 
-        Vec::new()
+        self.union = merge(self.union.drain(..).chain(spans.iter().cloned()).collect());
+
+        self.inner.push(range);
+        self.normalized.push(Some(spans));
+
+        true
     }
+}
 
-    pub async fn satisifies(&self, package: &str, version: &Version) -> bool {
-        {
-            if self.removed.read().await.contains(package) {
-                return false
-            }
+impl PackageRangeCache {
+    pub fn new(greedy: bool) -> Self {
+        Self { greedy, ..Default::default() }
+    }
+
+    pub fn max_satisfying<'a>(&self, package: &str, versions: &'a [Version]) -> Vec<&'a Version> {
+        if self.removed.contains(package) {
+            return Vec::new()
         }
 
-        let map = self.versions.read().await;
+        match self.versions.get(package) {
+            Some(ranges) => ranges.max_satisfying(versions),
+            None => Vec::new()
+        }
+    }
 
-        if let Some(ranges) = map.get(package) {
-            return ranges.satisfies(version)
+    pub fn satisifies(&self, package: &str, version: &Version) -> bool {
+        if self.removed.contains(package) {
+            return false
         }
 
-        false
+        self.versions.get(package)
+            .is_some_and(|ranges| ranges.satisfies(version))
     }
 
-    pub async fn remove(&self, package: &str) {
-        self.versions.write().await.remove(package);
-        self.removed.write().await.insert(package.to_compact_string());
+    pub fn remove(&self, package: &str) {
+        self.versions.remove(package);
+        self.removed.insert(package.to_compact_string());
     }
 
-    pub async fn insert(&self, package: &str, new_range: &Range) -> RangeCacheResult {
-        {
-            if self.removed.read().await.contains(package) {
-                return RangeCacheResult { package_is_new: false, range_is_new: false }
+    pub fn insert(&self, package: &str, new_range: &Range) -> RangeCacheResult {
+        if self.removed.contains(package) {
+            return RangeCacheResult { package_is_new: false, range_is_new: false }
+        }
+
+        // the entry keeps the shard locked, so parallel inserts of the same range can't both count as new
+        match self.versions.entry(package.to_compact_string()) {
+            Entry::Occupied(mut entry) => {
+                let range_is_new = entry.get_mut().insert(new_range.clone(), self.greedy);
+
+                RangeCacheResult { package_is_new: false, range_is_new }
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(Ranges::new(vec![new_range.clone()], self.greedy));
+                RangeCacheResult { package_is_new: true, range_is_new: true }
             }
         }
+    }
+
+    /// Records that a dist-tag of the package is needed, to be resolved once its metadata is available.
+    pub fn insert_tag(&self, package: &str, tag: &str) -> RangeCacheResult {
+        if self.removed.contains(package) {
+            return RangeCacheResult { package_is_new: false, range_is_new: false }
+        }
 
-        {
-            if let Some(ranges) = self.versions.read().await.get(package) {
-                for range in &ranges.inner {
-                    if new_range.allows_all(range) {
-                        return RangeCacheResult { package_is_new: false, range_is_new: false }
-                    }
+        match self.versions.entry(package.to_compact_string()) {
+            Entry::Occupied(mut entry) => {
+                let ranges = entry.get_mut();
+
+                if ranges.tags.iter().any(|t| t == tag) {
+                    return RangeCacheResult { package_is_new: false, range_is_new: false }
                 }
+
+                ranges.tags.push(tag.to_compact_string());
+                RangeCacheResult { package_is_new: false, range_is_new: true }
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(Ranges { tags: vec![tag.to_compact_string()], ..Default::default() });
+                RangeCacheResult { package_is_new: true, range_is_new: true }
             }
         }
+    }
 
-        {
-            let mut map = self.versions.write().await;
+    /// Adds the versions the dist-tags of the package point to as ranges, using its own metadata. Returns the
+    /// tags its metadata doesn't know.
+    pub fn resolve_tags(&self, package: &str, idx: &PackageIndex) -> Vec<CompactString> {
+        let mut unknown = Vec::new();
 
-            let new_range = new_range.clone();
+        let Some(mut ranges) = self.versions.get_mut(package) else {
+            return unknown
+        };
 
-            if let Some(ranges) = map.get_mut(package) {
-                ranges.inner.push(new_range);
-                RangeCacheResult { package_is_new: false, range_is_new: true }
+        for tag in ranges.tags.clone() {
+            match idx.version_by_tag(&tag).map(|v| Range::parse(v.to_string())) {
+                Some(Ok(range)) => {
+                    ranges.insert(range, self.greedy);
+                },
+                _ => unknown.push(tag)
+            }
+        }
+
+        unknown
+    }
+}
+
+pub struct RangeCacheResult { pub package_is_new: bool, pub range_is_new: bool }
+
+/// What a dependency asks for, a version range or a dist-tag of the dependency.
+#[derive(Clone, Debug)]
+pub enum Requirement {
+    Range(Range),
+    Tag(CompactString),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Bound {
+    Unbounded,
+    Including(Version),
+    Excluding(Version),
+}
+
+/// A single alternative of a range, one side of a `||`.
+#[derive(Clone, Debug, PartialEq)]
+struct Span {
+    lower: Bound,
+    upper: Bound,
+}
+
+impl Span {
+    fn parse_range(range: &Range) -> Option<Vec<Span>> {
+        range.to_string().split("||").map(Span::parse).collect()
+    }
+
+    /// Parses an alternative the way `Range` displays it, such as `>=1.2.0 <2.0.0-0`, `<=1.0.0`, `1.2.3` or `*`.
+    fn parse(s: &str) -> Option<Span> {
+        let mut span = Span { lower: Bound::Unbounded, upper: Bound::Unbounded };
+
+        for token in s.split_whitespace() {
+            if token == "*" {
+                continue
+            }
+
+            if let Some(v) = token.strip_prefix(">=") {
+                span.lower = Bound::Including(Version::parse(v).ok()?);
+            } else if let Some(v) = token.strip_prefix('>') {
+                span.lower = Bound::Excluding(Version::parse(v).ok()?);
+            } else if let Some(v) = token.strip_prefix("<=") {
+                span.upper = Bound::Including(Version::parse(v).ok()?);
+            } else if let Some(v) = token.strip_prefix('<') {
+                span.upper = Bound::Excluding(Version::parse(v).ok()?);
             } else {
-                map.insert(package.to_compact_string(), Ranges { inner: vec![new_range] });
-                RangeCacheResult { package_is_new: true, range_is_new: true }
+                let v = Version::parse(token).ok()?;
+                span.lower = Bound::Including(v.clone());
+                span.upper = Bound::Including(v);
+            }
+        }
+
+        Some(span)
+    }
+
+    fn satisfies(&self, version: &Version) -> bool {
+        let above_lower = match &self.lower {
+            Bound::Unbounded => true,
+            Bound::Including(v) => v <= version,
+            Bound::Excluding(v) => v < version,
+        };
+
+        let below_upper = match &self.upper {
+            Bound::Unbounded => true,
+            Bound::Including(v) => version <= v,
+            Bound::Excluding(v) => version < v,
+        };
+
+        if !above_lower || !below_upper {
+            return false
+        }
+
+        !version.is_prerelease() || self.prerelease_tuples().contains(&tuple(version))
+    }
+
+    /// Prereleases only match a range if one of its bounds is a prerelease of the same version. An upper bound
+    /// like the `<2.0.0-0` of `^1.0.0` doesn't count, as no prerelease of 2.0.0 is below it.
+    fn prerelease_tuples(&self) -> Vec<(u64, u64, u64)> {
+        let mut tuples = Vec::new();
+
+        if let Bound::Including(v) | Bound::Excluding(v) = &self.lower && v.is_prerelease() {
+            tuples.push(tuple(v));
+        }
+
+        match &self.upper {
+            Bound::Including(v) if v.is_prerelease() => tuples.push(tuple(v)),
+            Bound::Excluding(v) if v.is_prerelease() && v.pre_release != [Identifier::Numeric(0)] => tuples.push(tuple(v)),
+            _ => ()
+        }
+
+        tuples
+    }
+
+    fn contains(&self, other: &Span) -> bool {
+        cmp_lower(&self.lower, &other.lower) != Ordering::Greater &&
+            cmp_upper(&self.upper, &other.upper) != Ordering::Less &&
+            other.prerelease_tuples().iter().all(|t| self.prerelease_tuples().contains(t))
+    }
+
+    /// Whether a span starting at or after this one overlaps or directly follows it.
+    fn touches(&self, next: &Span) -> bool {
+        match (&self.upper, &next.lower) {
+            (Bound::Unbounded, _) | (_, Bound::Unbounded) => true,
+            (Bound::Excluding(upper), Bound::Excluding(lower)) => lower < upper,
+            (Bound::Including(upper) | Bound::Excluding(upper), Bound::Including(lower) | Bound::Excluding(lower)) => lower <= upper,
+        }
+    }
+}
+
+fn tuple(version: &Version) -> (u64, u64, u64) {
+    (version.major, version.minor, version.patch)
+}
+
+fn cmp_lower(a: &Bound, b: &Bound) -> Ordering {
+    match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Ordering::Equal,
+        (Bound::Unbounded, _) => Ordering::Less,
+        (_, Bound::Unbounded) => Ordering::Greater,
+        (Bound::Including(a), Bound::Excluding(b)) => a.cmp(b).then(Ordering::Less),
+        (Bound::Excluding(a), Bound::Including(b)) => a.cmp(b).then(Ordering::Greater),
+        (Bound::Including(a), Bound::Including(b)) |
+        (Bound::Excluding(a), Bound::Excluding(b)) => a.cmp(b),
+    }
+}
+
+fn cmp_upper(a: &Bound, b: &Bound) -> Ordering {
+    match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Ordering::Equal,
+        (Bound::Unbounded, _) => Ordering::Greater,
+        (_, Bound::Unbounded) => Ordering::Less,
+        (Bound::Including(a), Bound::Excluding(b)) => a.cmp(b).then(Ordering::Greater),
+        (Bound::Excluding(a), Bound::Including(b)) => a.cmp(b).then(Ordering::Less),
+        (Bound::Including(a), Bound::Including(b)) |
+        (Bound::Excluding(a), Bound::Excluding(b)) => a.cmp(b),
+    }
+}
+
+/// Merges overlapping and adjacent spans, sorted by their lower bound. Spans matching prereleases are left alone,
+/// as merging them would change which prereleases match.
+fn merge(mut spans: Vec<Span>) -> Vec<Span> {
+    spans.sort_by(|a, b| cmp_lower(&a.lower, &b.lower).then_with(|| cmp_upper(&a.upper, &b.upper)));
+    spans.dedup();
+
+    let mut merged: Vec<Span> = Vec::with_capacity(spans.len());
+
+    for span in spans {
+        if let Some(last) = merged.last_mut()
+            && last.prerelease_tuples().is_empty()
+            && span.prerelease_tuples().is_empty()
+            && last.touches(&span) {
+            if cmp_upper(&span.upper, &last.upper) == Ordering::Greater {
+                last.upper = span.upper;
             }
+
+            continue
         }
+
+        merged.push(span);
     }
+
+    merged
 }
 
-pub struct RangeCacheResult { pub package_is_new: bool, pub range_is_new: bool }
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> Range {
+        Range::parse(s).unwrap()
+    }
+
+    fn version(s: &str) -> Version {
+        Version::parse(s).unwrap()
+    }
+
+    fn versions(list: &[&str]) -> Vec<Version> {
+        list.iter().map(|v| version(v)).collect()
+    }
+
+    fn is_new(cache: &PackageRangeCache, r: &str) -> bool {
+        cache.insert("pkg", &range(r)).range_is_new
+    }
+
+    #[test]
+    fn greedy_skips_covered_ranges() {
+        let cache = PackageRangeCache::new(true);
+
+        assert!(is_new(&cache, "^1.2.0"));
+        assert!(is_new(&cache, "^1.0.0"), "a wider range is not covered by a narrower one");
+        assert!(!is_new(&cache, "~1.3.0"));
+        assert!(!is_new(&cache, "^1.2.0"));
+        assert!(!is_new(&cache, "1.0.0"));
+
+        assert_eq!(cache.versions.get("pkg").unwrap().union.len(), 1);
+    }
+
+    #[test]
+    fn greedy_merges_adjacent_ranges() {
+        let cache = PackageRangeCache::new(true);
+
+        assert!(is_new(&cache, ">=1.0.0 <1.5.0"));
+        assert!(is_new(&cache, ">=1.5.0 <2.0.0"));
+        assert!(!is_new(&cache, "~1.4.0 || ~1.5.0"), "only the merged union covers both");
+
+        assert!(cache.satisifies("pkg", &version("1.5.0")));
+        assert!(!cache.satisifies("pkg", &version("2.0.0")));
+    }
+
+    #[test]
+    fn gaps_are_not_merged() {
+        let cache = PackageRangeCache::new(true);
+
+        assert!(is_new(&cache, ">=1.0.0 <1.2.0"));
+        assert!(is_new(&cache, ">1.2.0 <2.0.0"));
+
+        assert!(!cache.satisifies("pkg", &version("1.2.0")));
+        assert!(is_new(&cache, "1.2.0"));
+        assert!(cache.satisifies("pkg", &version("1.2.0")));
+    }
+
+    #[test]
+    fn tilde_and_caret_select_their_own_versions() {
+        let cache = PackageRangeCache::new(false);
+
+        assert!(is_new(&cache, "^1.0.0"));
+        assert!(is_new(&cache, "~1.2.0"), "covered, but selects a different version");
+        assert!(!is_new(&cache, ">=1.0.0 <2.0.0-0"), "equivalent to ^1.0.0");
+
+        let available = versions(&["1.0.0", "1.2.0", "1.2.5", "1.9.0", "2.0.0"]);
+
+        assert_eq!(
+            cache.max_satisfying("pkg", &available),
+            vec![&available[3], &available[2]]
+        );
+    }
+
+    #[test]
+    fn alternatives() {
+        let cache = PackageRangeCache::new(true);
+
+        assert!(is_new(&cache, "^1.0.0 || ^2.0.0"));
+        assert!(!is_new(&cache, "~2.1.0"));
+        assert!(!is_new(&cache, "^1.5.0 || 2.3.4"));
+        assert!(is_new(&cache, "^2.0.0 || ^3.0.0"), "only partly covered");
+        assert!(!is_new(&cache, "^3.1.0"));
+
+        let cache = PackageRangeCache::new(false);
+
+        assert!(is_new(&cache, "^1.0.0 || ^2.0.0"));
+        assert!(!is_new(&cache, "^2.0.0 || ^1.0.0"), "the same alternatives in a different order");
+    }
+
+    #[test]
+    fn hyphen_ranges() {
+        let cache = PackageRangeCache::new(true);
+
+        assert!(is_new(&cache, "1.2.3 - 2.3.4"));
+        assert!(!is_new(&cache, "^1.5.0"));
+        assert!(!is_new(&cache, "2.0.0 - 2.3.4"));
+        assert!(is_new(&cache, "^2.3.0"), "goes past the end of the hyphen range");
+
+        assert!(cache.satisifies("pkg", &version("1.2.3")));
+        assert!(!cache.satisifies("pkg", &version("1.2.2")));
+    }
+
+    #[test]
+    fn prerelease_ranges() {
+        let cache = PackageRangeCache::new(true);
+
+        assert!(is_new(&cache, ">=1.0.0"));
+        assert!(!cache.satisifies("pkg", &version("1.2.3-beta.2")));
+
+        assert!(is_new(&cache, "^1.2.3-beta.1"), "matches prereleases of 1.2.3 that >=1.0.0 doesn't");
+        assert!(!is_new(&cache, "^1.2.3-beta.1"));
+        assert!(cache.satisifies("pkg", &version("1.2.3-beta.2")));
+        assert!(!cache.satisifies("pkg", &version("1.2.4-beta.1")));
+        assert!(!cache.satisifies("pkg", &version("1.2.3-alpha.1")));
+
+        let cache = PackageRangeCache::new(true);
+
+        assert!(is_new(&cache, ">=1.0.0 <1.5.0"));
+        assert!(is_new(&cache, "^1.5.0"));
+        assert!(!cache.satisifies("pkg", &version("1.6.0-rc.1")), "merging ranges doesn't let prereleases in");
+        assert!(!cache.satisifies("pkg", &version("2.0.0-0")));
+    }
+
+    #[test]
+    fn matches_range_satisfies() {
+        let candidates = versions(&[
+            "0.9.0", "1.0.0", "1.2.3-beta.1", "1.2.3-beta.2", "1.2.3", "1.2.9", "1.3.0-rc.1", "1.3.0", "1.9.9",
+            "2.0.0-0", "2.0.0", "2.3.4", "2.3.5", "3.0.0",
+        ]);
+
+        for list in [
+            vec!["^1.0.0", "~1.2.0"],
+            vec!["~1.2.3-beta.1", "^1.3.0"],
+            vec!["1.2.3 - 2.3.4", ">=3.0.0"],
+            vec!["<1.0.0 || >=2.0.0", "1.3.0"],
+            vec!["*"],
+        ] {
+            let ranges = Ranges::new(list.iter().map(|r| range(r)).collect(), true);
+
+            for v in &candidates {
+                let expected = list.iter().any(|r| range(r).satisfies(v));
+                assert_eq!(ranges.satisfies(v), expected, "{v} in {list:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn tags_resolve_against_own_metadata() {
+        let cache = PackageRangeCache::new(false);
+
+        assert!(cache.insert_tag("pkg", "latest").package_is_new);
+        assert!(!cache.insert_tag("pkg", "latest").range_is_new);
+        assert!(cache.insert_tag("pkg", "next").range_is_new);
+        assert!(cache.insert_tag("pkg", "beta").range_is_new);
+
+        let idx = PackageIndex {
+            versions: versions(&["1.0.0", "2.0.0-rc.1", "2.0.0"]),
+            dist_tags: [("latest".to_string(), 0), ("next".to_string(), 1)].into_iter().collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(cache.resolve_tags("pkg", &idx), vec!["beta"]);
+        assert_eq!(cache.max_satisfying("pkg", &idx.versions), vec![&idx.versions[0], &idx.versions[1]]);
+    }
+
+    #[test]
+    fn removed_packages_take_no_ranges() {
+        let cache = PackageRangeCache::new(false);
+
+        assert!(cache.insert("pkg", &range("^1.0.0")).package_is_new);
+        cache.remove("pkg");
+
+        let res = cache.insert("pkg", &range("^2.0.0"));
+        assert!(!res.package_is_new && !res.range_is_new);
+    }
+}
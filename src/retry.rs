@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+
+use crate::CliOpts;
+
+pub struct RetryPolicy {
+    pub retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(opts: &CliOpts) -> Self {
+        Self {
+            retries: opts.retries,
+            base_delay: opts.retry_delay,
+            max_delay: opts.retry_max_delay,
+        }
+    }
+
+    /// The delay before the given retry (starting at 1), using exponential backoff with full jitter. A `Retry-After`
+    /// given by the server takes precedence if it asks for a longer wait, unless it's beyond the maximum delay, in
+    /// which case there is no retry at all.
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        let backoff = self.base_delay
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+            .min(self.max_delay);
+
+        let jittered = backoff.mul_f64(fastrand::f64());
+
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after.max(jittered)),
+            None => Some(jittered)
+        }
+    }
+}
+
+/// Parses a `Retry-After` header, which is either a number of seconds or an http date.
+pub fn get_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds))
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+
+    (date.to_utc() - chrono::Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy { retries: 10, base_delay: Duration::from_millis(100), max_delay: Duration::from_secs(2) }
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        HeaderMap::from_iter([(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap())])
+    }
+
+    #[test]
+    fn backoff_is_jittered_below_its_cap() {
+        let policy = policy();
+
+        for _ in 0..100 {
+            assert!(policy.delay(1, None).unwrap() <= Duration::from_millis(100));
+            assert!(policy.delay(3, None).unwrap() <= Duration::from_millis(400));
+            assert!(policy.delay(10, None).unwrap() <= Duration::from_secs(2));
+            assert!(policy.delay(u32::MAX, None).unwrap() <= Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn retry_after_takes_precedence() {
+        let policy = policy();
+
+        for _ in 0..100 {
+            assert_eq!(policy.delay(1, Some(Duration::from_secs(1))), Some(Duration::from_secs(1)));
+            assert_eq!(policy.delay(10, Some(Duration::from_secs(2))), Some(Duration::from_secs(2)));
+            assert!(policy.delay(10, Some(Duration::ZERO)).unwrap() <= Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn retry_after_beyond_the_maximum_gives_up() {
+        assert_eq!(policy().delay(1, Some(Duration::from_secs(3))), None);
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(get_retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(get_retry_after(&headers(" 5 ")), Some(Duration::from_secs(5)));
+        assert_eq!(get_retry_after(&HeaderMap::new()), None);
+        assert_eq!(get_retry_after(&headers("soon")), None);
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        let in_a_minute = (chrono::Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let retry_after = get_retry_after(&headers(&in_a_minute)).unwrap();
+
+        assert!(retry_after > Duration::from_secs(55) && retry_after <= Duration::from_secs(60));
+
+        let an_hour_ago = (chrono::Utc::now() - chrono::Duration::hours(1)).to_rfc2822();
+        assert_eq!(get_retry_after(&headers(&an_hour_ago)), None);
+
+        assert_eq!(get_retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")), None);
+    }
+}
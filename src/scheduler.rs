@@ -0,0 +1,243 @@
+use std::{cmp::{Ordering, Reverse}, collections::BinaryHeap, sync::Mutex};
+
+use ahash::{HashMap, HashSet};
+use compact_str::CompactString;
+use tokio::sync::Notify;
+
+use crate::concurrency::{host_of, Concurrency, Permit};
+use crate::downloader::Download;
+
+/// The order downloads are started in. Metadata comes first, as it gates the next round of resolving, then
+/// tarballs from small to large. Tarballs of unknown size come last.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Priority {
+    Metadata,
+    Tarball(u64),
+}
+
+impl Priority {
+    pub fn of(download: &Download) -> Self {
+        match download {
+            Download::Metadata { .. } => Priority::Metadata,
+            Download::Tarball { size, .. } => Priority::Tarball(size.unwrap_or(u64::MAX)),
+        }
+    }
+}
+
+/// How many downloads may wait in line before queueing more waits for room, so that resolving far ahead of
+/// downloading doesn't pile up every download of the run in memory.
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// Hands out queued downloads by priority, first come first served within the same priority. A url is only queued
+/// again once its download finished. Downloads are queued per host, so that a host at its limit doesn't hold up the
+/// others.
+pub struct Scheduler {
+    capacity: usize,
+    state: Mutex<State>,
+    notify: Notify,
+    room: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    queues: HashMap<Option<CompactString>, BinaryHeap<Reverse<Queued>>>,
+    len: usize,
+    /// The urls of downloads queued or in flight.
+    seen: HashSet<String>,
+    seq: u64,
+}
+
+struct Queued {
+    priority: Priority,
+    seq: u64,
+    download: Download,
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority)
+            .then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(QUEUE_CAPACITY)
+    }
+}
+
+impl Scheduler {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(State::default()),
+            notify: Notify::new(),
+            room: Notify::new(),
+        }
+    }
+
+    /// Marks the url of the download as queued, returning false if it already is queued or in flight.
+    pub fn claim(&self, download: &Download) -> bool {
+        self.state.lock().unwrap().seen.insert(download.url().to_string())
+    }
+
+    /// Forgets the url of a finished download.
+    pub fn finish(&self, download: &Download) {
+        self.state.lock().unwrap().seen.remove(download.url());
+    }
+
+    /// Queues a download claimed before, waiting for room if the queue is full.
+    pub async fn push(&self, download: Download) {
+        loop {
+            let room = self.room.notified();
+            tokio::pin!(room);
+            room.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if state.len < self.capacity {
+                    let seq = state.seq;
+                    state.seq += 1;
+                    state.len += 1;
+
+                    state.queues.entry(host_of(download.url()))
+                        .or_default()
+                        .push(Reverse(Queued { priority: Priority::of(&download), seq, download }));
+
+                    break
+                }
+            }
+
+            room.await;
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next download in line that may start right away, along with the permit to run it. Downloads
+    /// stay queued while their host is at its limit.
+    pub async fn pop<'a>(&self, concurrency: &'a Concurrency) -> (Download, Permit<'a>) {
+        loop {
+            let pushed = self.notify.notified();
+            let capacity_changed = concurrency.capacity_changed();
+            tokio::pin!(pushed, capacity_changed);
+            pushed.as_mut().enable();
+            capacity_changed.as_mut().enable();
+
+            if let Some(next) = self.try_pop(concurrency) {
+                return next
+            }
+
+            tokio::select! {
+                _ = pushed => (),
+                _ = capacity_changed => ()
+            }
+        }
+    }
+
+    fn try_pop<'a>(&self, concurrency: &'a Concurrency) -> Option<(Download, Permit<'a>)> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut hosts: Vec<(&Reverse<Queued>, &Option<CompactString>)> = state.queues.iter()
+            .filter_map(|(host, queue)| Some((queue.peek()?, host)))
+            .collect();
+
+        hosts.sort_by(|a, b| b.0.cmp(a.0));
+
+        let (host, permit) = hosts.into_iter()
+            .find_map(|(_, host)| Some((host.clone(), concurrency.try_acquire(host.as_ref())?)))?;
+
+        let queue = state.queues.get_mut(&host)?;
+        let Reverse(queued) = queue.pop()?;
+
+        if queue.is_empty() {
+            state.queues.remove(&host);
+        }
+
+        state.len -= 1;
+        self.room.notify_one();
+
+        Some((queued.download, permit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use crate::CliOpts;
+
+    use super::*;
+
+    fn tarball(url: &str, size: u64) -> Download {
+        Download::Tarball { url: url.to_string(), target_path: "/mirror/x.tgz".into(), checksum: None, size: Some(size), source: None }
+    }
+
+    #[tokio::test]
+    async fn busy_hosts_dont_hold_up_others() {
+        let opts = CliOpts::parse_from(["npmmirs", "--dl-threads", "4", "--max-per-host", "1"]);
+        let concurrency = Concurrency::new(&opts);
+        let scheduler = Scheduler::default();
+
+        for dl in [tarball("https://a.test/1.tgz", 1), tarball("https://a.test/2.tgz", 2), tarball("https://b.test/3.tgz", 3)] {
+            scheduler.push(dl).await;
+        }
+
+        let (first, first_permit) = scheduler.try_pop(&concurrency).unwrap();
+        assert_eq!(first.url(), "https://a.test/1.tgz");
+
+        let (second, _second_permit) = scheduler.try_pop(&concurrency).unwrap();
+        assert_eq!(second.url(), "https://b.test/3.tgz");
+        assert!(scheduler.try_pop(&concurrency).is_none());
+
+        drop(first_permit);
+        assert_eq!(scheduler.try_pop(&concurrency).unwrap().0.url(), "https://a.test/2.tgz");
+    }
+
+    #[tokio::test]
+    async fn full_queue_waits_for_room() {
+        let opts = CliOpts::parse_from(["npmmirs", "--dl-threads", "4"]);
+        let concurrency = Concurrency::new(&opts);
+        let scheduler = Scheduler::new(1);
+
+        scheduler.push(tarball("https://a.test/1.tgz", 1)).await;
+
+        let push = scheduler.push(tarball("https://a.test/2.tgz", 2));
+        tokio::pin!(push);
+        assert!(tokio::time::timeout(Duration::from_millis(20), push.as_mut()).await.is_err());
+
+        let (first, _permit) = scheduler.try_pop(&concurrency).unwrap();
+        assert_eq!(first.url(), "https://a.test/1.tgz");
+        push.await;
+        assert_eq!(scheduler.try_pop(&concurrency).unwrap().0.url(), "https://a.test/2.tgz");
+    }
+
+    #[test]
+    fn finished_urls_can_be_queued_again() {
+        let scheduler = Scheduler::default();
+        let dl = tarball("https://a.test/1.tgz", 1);
+
+        assert!(scheduler.claim(&dl));
+        assert!(!scheduler.claim(&dl));
+
+        scheduler.finish(&dl);
+        assert!(scheduler.claim(&dl));
+    }
+}
@@ -0,0 +1,46 @@
+use std::{process::exit, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::log;
+
+/// Tracks whether the user asked us to stop, via SIGINT or SIGTERM.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// Starts listening for SIGINT and SIGTERM. The first signal triggers a graceful shutdown, a second one exits
+    /// immediately.
+    pub fn listen() -> Self {
+        let shutdown = Self::default();
+
+        let mut sigint = signal(SignalKind::interrupt()).expect("unable to listen for SIGINT");
+        let mut sigterm = signal(SignalKind::terminate()).expect("unable to listen for SIGTERM");
+
+        let triggered = shutdown.triggered.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = sigint.recv() => (),
+                    _ = sigterm.recv() => (),
+                }
+
+                if triggered.swap(true, Ordering::SeqCst) {
+                    log("Stopping immediately");
+                    exit(130)
+                }
+
+                log("Shutting down, waiting for in-flight downloads. Signal again to stop immediately");
+            }
+        });
+
+        shutdown
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use ahash::HashSet;
+use compact_str::CompactString;
+use nodejs_semver::{Range, Version};
+use serde::{Deserialize, Serialize};
+
+use crate::{atomic_file::write_atomic, downloader::create_dirs, error::ErrorKind, range_cache::{PackageRangeCache, Ranges}, CliOpts};
+
+/// The package versions whose dependencies have been expanded, the packages still waiting to be expanded, and the
+/// packages whose metadata was still downloading.
+#[derive(Default)]
+pub struct Frontier {
+    pub visited: HashSet<(CompactString, Version)>,
+    pub pending: Vec<CompactString>,
+    pub awaiting: Vec<CompactString>,
+}
+
+/// The resolution state of an interrupted run, which lets `--resume` continue where it left off.
+#[derive(Serialize, Deserialize)]
+pub struct RunState {
+    ranges: Vec<(CompactString, Vec<Range>, Vec<CompactString>)>,
+    removed: Vec<CompactString>,
+    visited: Vec<(CompactString, Version)>,
+    pending: Vec<CompactString>,
+    awaiting: Vec<CompactString>,
+}
+
+impl RunState {
+    pub fn capture(range_cache: &PackageRangeCache, frontier: &Frontier) -> Self {
+        Self {
+            ranges: range_cache.versions.iter()
+                .map(|entry| (entry.key().clone(), entry.value().inner.clone(), entry.value().tags.clone()))
+                .collect(),
+            removed: range_cache.removed.iter().map(|package| package.clone()).collect(),
+            visited: frontier.visited.iter().cloned().collect(),
+            pending: frontier.pending.clone(),
+            awaiting: frontier.awaiting.clone(),
+        }
+    }
+
+    pub fn restore(self, greedy: bool) -> (PackageRangeCache, Frontier) {
+        let range_cache = PackageRangeCache::new(greedy);
+
+        for (package, inner, tags) in self.ranges {
+            let mut ranges = Ranges::new(inner, greedy);
+            ranges.tags = tags;
+
+            range_cache.versions.insert(package, ranges);
+        }
+
+        for package in self.removed {
+            range_cache.removed.insert(package);
+        }
+
+        let frontier = Frontier {
+            visited: self.visited.into_iter().collect(),
+            pending: self.pending,
+            awaiting: self.awaiting,
+        };
+
+        (range_cache, frontier)
+    }
+}
+
+pub async fn save_state(opts: &CliOpts, state: &RunState) -> Result<(), ErrorKind> {
+    let data = bitcode::serialize(state)?;
+    let compressed = zstd::encode_all(&data[..], 3)?;
+
+    let path = state_path(opts);
+
+    create_dirs(&path).await?;
+    write_atomic(opts, &path, &compressed).await
+}
+
+pub async fn load_state(opts: &CliOpts) -> Result<Option<RunState>, ErrorKind> {
+    let compressed = match tokio::fs::read(state_path(opts)).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into())
+    };
+
+    let data = zstd::decode_all(&compressed[..])?;
+
+    Ok(Some(bitcode::deserialize(&data)?))
+}
+
+pub async fn remove_state(opts: &CliOpts) -> Result<(), ErrorKind> {
+    match tokio::fs::remove_file(state_path(opts)).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into())
+    }
+}
+
+fn state_path(opts: &CliOpts) -> PathBuf {
+    let output_base = opts.output.strip_suffix('/').unwrap_or(&opts.output);
+
+    PathBuf::from(format!("{output_base}/.npmmirs-state"))
+}
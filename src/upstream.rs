@@ -0,0 +1,40 @@
+use std::path::{Component, Path, PathBuf};
+
+use reqwest::Url;
+
+/// The local directory a registry url points to, for mirroring from the output folder of another mirror. Both
+/// `file://` urls and plain paths are accepted, anything over http(s) is not local.
+pub fn local_path(url: &str) -> Option<PathBuf> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return None
+    }
+
+    if url.starts_with("file://") {
+        return Url::parse(url).ok()?.to_file_path().ok()
+    }
+
+    Some(PathBuf::from(url))
+}
+
+/// The path as a relative path that stays below whatever it's joined onto, so no `..`, root or empty paths. Package
+/// names and tarball paths come from metadata, which mustn't get to pick files outside of the mirror.
+pub fn contained_path(path: &str) -> Option<&Path> {
+    let path = Path::new(path);
+
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None
+    }
+
+    Some(path)
+}
+
+/// The name a metadata link points to, which is the etag of the document. Regular files and links pointing
+/// anywhere but next to themselves have none.
+pub async fn link_name(path: &Path) -> std::io::Result<Option<String>> {
+    match tokio::fs::read_link(path).await {
+        Ok(target) if target.components().count() == 1 => Ok(target.to_str().map(|v| v.to_string())),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound || e.kind() == std::io::ErrorKind::InvalidInput => Ok(None),
+        Err(e) => Err(e)
+    }
+}